@@ -0,0 +1,517 @@
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use eiji_sub_ngram::header::{self, Kind, Problem};
+use eiji_sub_ngram::{
+    DataDirs, Dictionary, Folding, Page, Registry, SearchHit, SearchOptions, Suggestion,
+};
+
+use crate::print;
+
+/// How many headwords are suggested when a query finds nothing.
+const SUGGESTIONS: usize = 5;
+
+/// How long `serve` waits for the request line of a client.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Verifies the generated files of a dictionary, rebuilding them when they are
+/// missing or stale, `rebuild` is set and the dictionary is built
+/// automatically. Tells whether the dictionary can be searched.
+pub fn check(dict: &Dictionary, folding: Folding, dirs: &DataDirs, rebuild: bool) -> bool {
+    let sources_exist = dict.sources_exist();
+    match dict.verify(folding) {
+        Ok(()) => true,
+        Err(problem) if rebuild && dict.auto_build && sources_exist => {
+            println!("{}: {}, rebuilding the index", dict.name, problem);
+            build_index(dict, folding)
+        }
+        Err(Problem::Missing(_)) if !sources_exist => {
+            let report = format!("{}: not found, searched {}", dict.name, dirs);
+            // optional dictionaries are only reported when asked for
+            if dict.auto_build {
+                eprintln!(
+                    "{}",
+                    print::paint("1;31", &report, print::colored(&io::stderr()))
+                );
+            } else {
+                info!("{}", report);
+            }
+            false
+        }
+        Err(problem) => {
+            let report = format!("{}: {}, skipped", dict.name, problem);
+            eprintln!(
+                "{}",
+                print::paint("1;31", &report, print::colored(&io::stderr()))
+            );
+            false
+        }
+    }
+}
+
+/// Builds the index of a dictionary, telling where it goes when the
+/// directory of the sources cannot be written. Tells whether it was built.
+fn build_index(dict: &Dictionary, folding: Folding) -> bool {
+    let sources = dict.sources.join(" and ");
+    match dict.relocated() {
+        Some(dir) => println!(
+            "{}: cannot write next to {}, building the index in {}",
+            dict.name,
+            sources,
+            dir.display()
+        ),
+        None => println!("building the index of {}", sources),
+    }
+    match dict.build(folding) {
+        Ok(()) => {
+            println!("indexing finished successfully.");
+            true
+        }
+        Err(e) => {
+            let report = format!("{}: {}", dict.name, e);
+            eprintln!(
+                "{}",
+                print::paint("1;31", &report, print::colored(&io::stderr()))
+            );
+            false
+        }
+    }
+}
+
+/// The named dictionaries, or every enabled one when there are no names.
+fn select<'a>(registry: &'a Registry, names: &[String]) -> Result<Vec<&'a Dictionary>, String> {
+    if names.is_empty() {
+        return Ok(registry.enabled().collect());
+    }
+    names
+        .iter()
+        .map(|name| {
+            registry
+                .get(name)
+                .ok_or_else(|| format!("unknown dictionary {}", name))
+        })
+        .collect()
+}
+
+/// How `lookup` and `serve` write hits.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    /// `headword<TAB>body` lines
+    Plain,
+    /// A JSON array of hits
+    Json,
+    /// One JSON hit per line
+    Ndjson,
+}
+
+impl OutputFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Plain => "text/plain; charset=utf-8",
+            OutputFormat::Json => "application/json",
+            OutputFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// The hits in this format, ending with a newline.
+    fn render(self, hits: &[SearchHit]) -> String {
+        let mut out = match self {
+            OutputFormat::Plain => hits.iter().map(print::plain).collect::<Vec<_>>().join("\n"),
+            OutputFormat::Json => serde_json::to_string(hits).unwrap(),
+            OutputFormat::Ndjson => hits
+                .iter()
+                .map(|hit| serde_json::to_string(hit).unwrap())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Hits of the input in the dictionaries, ranked together and followed by
+/// those of its base forms, then the page of them from `offset` on, at most
+/// `limit` of them. A leading `/` searches by regular expression, which is an
+/// error when invalid, a leading `~` matches headwords against a wildcard
+/// pattern and a trailing `*` searches headwords by prefix.
+fn find_hits(
+    input: &str,
+    dicts: &[&Dictionary],
+    registry: &Registry,
+    offset: usize,
+    limit: Option<usize>,
+) -> Result<Page, String> {
+    let (query, options) = SearchOptions::for_query(input, registry.normalize);
+    options.query(query).map_err(|e| e.to_string())?;
+    let options = SearchOptions {
+        offset,
+        limit,
+        ..options
+    };
+    Ok(registry.lemmatizer.search_page(dicts, query, &options))
+}
+
+/// Headwords of the dictionaries close to an ordinary query which found
+/// nothing, the closest first. None for regular expressions and patterns.
+pub fn suggest(input: &str, dicts: &[&Dictionary], folding: Folding) -> Vec<String> {
+    let (query, options) = SearchOptions::for_query(input, folding);
+    if options.regex || options.wildcard || options.prefix {
+        return vec![];
+    }
+    let mut found = dicts
+        .iter()
+        .filter(|dict| dict.verify(Folding::None).is_ok())
+        .flat_map(|dict| dict.suggest(query, SUGGESTIONS))
+        .collect::<Vec<Suggestion>>();
+    found.sort();
+    let mut headwords: Vec<String> = vec![];
+    for suggestion in found {
+        if !headwords.contains(&suggestion.headword) {
+            headwords.push(suggestion.headword);
+        }
+    }
+    headwords.truncate(SUGGESTIONS);
+    headwords
+}
+
+/// Prints the hits without the pager, telling on stderr how many were left
+/// out. Returns the exit code: 0 when something was printed, 1 when nothing
+/// was and 2 for unknown dictionaries or an invalid regular expression.
+pub fn lookup(
+    input: &str,
+    names: &[String],
+    offset: usize,
+    limit: Option<usize>,
+    format: OutputFormat,
+    registry: &Registry,
+    dirs: &DataDirs,
+) -> i32 {
+    let dicts = match select(registry, names) {
+        Ok(dicts) => dicts
+            .into_iter()
+            .filter(|dict| check(dict, registry.normalize, dirs, false))
+            .collect::<Vec<&Dictionary>>(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let page = match find_hits(input, &dicts, registry, offset, limit) {
+        Ok(page) => page,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    // an empty JSON array still tells the caller there was nothing
+    if !page.hits.is_empty() || format == OutputFormat::Json {
+        print!("{}", format.render(&page.hits));
+    }
+    if page.is_partial() {
+        eprintln!("{}", page.summary());
+    }
+    if page.total == 0 {
+        let suggestions = suggest(input, &dicts, registry.normalize);
+        if !suggestions.is_empty() {
+            eprintln!("did you mean: {}", suggestions.join(", "));
+        }
+    }
+    if page.hits.is_empty() {
+        1
+    } else {
+        0
+    }
+}
+
+/// Builds the index of the named dictionaries, or of every enabled one whose
+/// sources exist when `all` is set.
+pub fn build(names: &[String], all: bool, registry: &Registry, dirs: &DataDirs) -> i32 {
+    let dicts = match select(registry, names) {
+        Ok(dicts) => dicts,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let mut code = 0;
+    for dict in dicts {
+        if dict.sources_exist() {
+            if !build_index(dict, registry.normalize) {
+                code = 1;
+            }
+        } else if !all {
+            eprintln!(
+                "{}: {} not found, searched {}",
+                dict.name,
+                dict.sources.join(" and "),
+                dirs
+            );
+            code = 1;
+        }
+    }
+    code
+}
+
+/// Checks the generated files of every enabled dictionary. Dictionaries that
+/// are not installed are reported but do not fail the check.
+pub fn verify(registry: &Registry, dirs: &DataDirs) -> i32 {
+    let mut code = 0;
+    for dict in registry.enabled() {
+        match dict.verify(registry.normalize) {
+            Ok(()) => println!("{}: ok", dict.name),
+            Err(Problem::Missing(_)) if !dict.sources_exist() => {
+                println!("{}: not found, searched {}", dict.name, dirs)
+            }
+            Err(problem) => {
+                println!("{}: {}", dict.name, problem);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+/// Prints the size and the number of records of every generated file.
+pub fn stats(registry: &Registry) {
+    for dict in registry.enabled() {
+        println!("{}", dict.name);
+        for (file, kind) in dict.files(registry.normalize) {
+            match (fs::metadata(&file), header::records(&file)) {
+                (Ok(meta), _) if kind == Kind::Text => {
+                    println!("  {:<32} {:>14} bytes", file, meta.len())
+                }
+                (Ok(meta), Some(records)) => {
+                    println!(
+                        "  {:<32} {:>14} bytes {:>12} records",
+                        file,
+                        meta.len(),
+                        records
+                    )
+                }
+                _ => println!("  {:<32} missing", file),
+            }
+        }
+    }
+}
+
+/// Answers `GET /lookup?q=<word>[&dict=a,b][&offset=N][&limit=N][&format=json]`
+/// with the same output as `lookup`, one request at a time. The total number
+/// of hits goes in the `X-Total-Count` header. The generated files are only
+/// checked once, before listening. Returns 1 when the address cannot be
+/// listened on.
+pub fn serve(addr: &str, registry: &Registry, dirs: &DataDirs) -> i32 {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("{}: {}", addr, e);
+            return 1;
+        }
+    };
+    // disabled dictionaries can still be asked for, without being reported
+    let usable = registry
+        .dictionaries
+        .iter()
+        .filter(|dict| {
+            if dict.enabled {
+                check(dict, registry.normalize, dirs, false)
+            } else {
+                dict.verify(registry.normalize).is_ok()
+            }
+        })
+        .map(|dict| dict.name.as_str())
+        .collect::<Vec<&str>>();
+    println!("listening on http://{}/lookup?q=", addr);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => respond(stream, registry, &usable),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    0
+}
+
+/// What a request asks `serve` to look up.
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    input: String,
+    names: Vec<String>,
+    offset: usize,
+    limit: Option<usize>,
+    format: OutputFormat,
+}
+
+impl Request {
+    /// Reads the query string of `/lookup`, ignoring unknown keys and
+    /// numbers which do not parse.
+    fn parse(query: &str) -> Result<Request, String> {
+        let mut request = Request {
+            input: String::new(),
+            names: vec![],
+            offset: 0,
+            limit: None,
+            format: OutputFormat::Plain,
+        };
+        for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+            let value = percent_decode(value);
+            match key {
+                "q" => request.input = value,
+                "dict" => request.names = value.split(',').map(String::from).collect(),
+                "offset" => request.offset = value.parse().unwrap_or(request.offset),
+                "limit" => request.limit = value.parse().ok().or(request.limit),
+                "format" => request.format = OutputFormat::from_str(&value, true)?,
+                _ => {}
+            }
+        }
+        if request.input.trim().is_empty() {
+            return Err("q is required".to_string());
+        }
+        Ok(request)
+    }
+}
+
+fn respond(mut stream: TcpStream, registry: &Registry, usable: &[&str]) {
+    if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        warn!("{}", e);
+    }
+    let mut request = String::new();
+    if BufReader::new(&stream).read_line(&mut request).is_err() {
+        return;
+    }
+    let target = match request.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", target, _] => target,
+        _ => return reply(&mut stream, "405 Method Not Allowed", "", ""),
+    };
+    let query = match target.split_once('?') {
+        Some(("/lookup", query)) => query,
+        _ => return reply(&mut stream, "404 Not Found", "", ""),
+    };
+    let request = match Request::parse(query) {
+        Ok(request) => request,
+        Err(e) => return reply(&mut stream, "400 Bad Request", "", &(e + "\n")),
+    };
+    let dicts = match select(registry, &request.names) {
+        Ok(dicts) => dicts
+            .into_iter()
+            .filter(|dict| usable.contains(&dict.name.as_str()))
+            .collect::<Vec<&Dictionary>>(),
+        Err(e) => return reply(&mut stream, "400 Bad Request", "", &(e + "\n")),
+    };
+    let page = match find_hits(
+        &request.input,
+        &dicts,
+        registry,
+        request.offset,
+        request.limit,
+    ) {
+        Ok(page) => page,
+        Err(e) => return reply(&mut stream, "400 Bad Request", "", &(e + "\n")),
+    };
+    let status = if page.total == 0 {
+        "404 Not Found"
+    } else {
+        "200 OK"
+    };
+    let total = format!("X-Total-Count: {}\r\n", page.total);
+    reply_with(
+        &mut stream,
+        status,
+        request.format.content_type(),
+        &total,
+        &request.format.render(&page.hits),
+    );
+}
+
+/// Writes the response, plain text unless `content_type` says otherwise.
+fn reply(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) {
+    reply_with(stream, status, content_type, "", body)
+}
+
+/// Like `reply`, with `headers` ending in CRLF added after the usual ones.
+fn reply_with(stream: &mut TcpStream, status: &str, content_type: &str, headers: &str, body: &str) {
+    let content_type = match content_type {
+        "" => OutputFormat::Plain.content_type(),
+        _ => content_type,
+    };
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        headers,
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        warn!("{}", e);
+    }
+}
+
+/// Decodes `%XX` escapes and `+` of a query string value.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percent_decode, OutputFormat, Request};
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("%E3%83%8D%E3%82%B3"), "ネコ");
+        assert_eq!(percent_decode("a+cat"), "a cat");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(percent_decode("%E3%83"), "\u{fffd}");
+    }
+
+    #[test]
+    fn parses_the_query_string() {
+        let request =
+            Request::parse("q=%E7%8C%AB&dict=edict,eijiro&offset=2&limit=5&format=json").unwrap();
+        assert_eq!(
+            request,
+            Request {
+                input: "猫".to_string(),
+                names: vec!["edict".to_string(), "eijiro".to_string()],
+                offset: 2,
+                limit: Some(5),
+                format: OutputFormat::Json,
+            }
+        );
+        let request = Request::parse("q=cat&offset=x&limit=-1&page=3&dict").unwrap();
+        assert_eq!((request.offset, request.limit), (0, None));
+        assert!(request.names.is_empty());
+        assert!(Request::parse("q=cat&format=xml").is_err());
+        assert_eq!(
+            Request::parse("q=+&dict=edict").unwrap_err(),
+            "q is required"
+        );
+        assert!(Request::parse("").is_err());
+    }
+}
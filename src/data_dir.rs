@@ -0,0 +1,142 @@
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Subdirectory of the XDG data directories holding the dictionaries.
+const APP_DIR: &str = "websters1913";
+
+/// Overrides the search path, as `--data-dir` does.
+const DATA_DIR_ENV: &str = "EIJI_DATA_DIR";
+
+const DEFAULT_DATA_DIRS: &str = "/usr/local/share:/usr/share";
+
+/// Directories searched for the registry, the dictionary sources and the
+/// generated files, in order of preference.
+pub struct DataDirs {
+    dirs: Vec<PathBuf>,
+}
+
+impl DataDirs {
+    /// `--data-dir`, then `EIJI_DATA_DIR`, replaces the search path. Otherwise
+    /// `$XDG_DATA_HOME/websters1913`, `websters1913` under every entry of
+    /// `$XDG_DATA_DIRS` and the working directory are searched. The variables
+    /// are looked up with `var`, usually `std::env::var_os`.
+    pub fn resolve(flag: Option<PathBuf>, var: impl Fn(&str) -> Option<OsString>) -> DataDirs {
+        let non_empty = |name: &str| var(name).filter(|v| !v.is_empty());
+        if let Some(dir) = flag.or_else(|| non_empty(DATA_DIR_ENV).map(PathBuf::from)) {
+            return DataDirs { dirs: vec![dir] };
+        }
+        let mut dirs = vec![];
+        let home = non_empty("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| non_empty("HOME").map(|h| Path::new(&h).join(".local/share")));
+        dirs.extend(home.map(|d| d.join(APP_DIR)));
+        let system = non_empty("XDG_DATA_DIRS").unwrap_or_else(|| DEFAULT_DATA_DIRS.into());
+        // relative entries are invalid according to the XDG spec
+        dirs.extend(
+            env::split_paths(&system)
+                .filter(|d| d.is_absolute())
+                .map(|d| d.join(APP_DIR)),
+        );
+        dirs.push(PathBuf::from("."));
+        DataDirs { dirs }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Path> {
+        self.dirs.iter().map(PathBuf::as_path)
+    }
+
+    /// The directory new files go to when nothing was found.
+    pub fn first(&self) -> &Path {
+        &self.dirs[0]
+    }
+
+    /// The first directory containing `file`.
+    pub fn find(&self, file: &str) -> Option<&Path> {
+        self.iter().find(|dir| dir.join(file).exists())
+    }
+}
+
+impl fmt::Display for DataDirs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let dirs = self
+            .iter()
+            .map(|d| d.display().to_string())
+            .collect::<Vec<_>>();
+        write!(f, "{}", dirs.join(", "))
+    }
+}
+
+#[cfg(test)]
+impl DataDirs {
+    /// Exactly these directories, in this order.
+    pub fn of(dirs: &[&Path]) -> DataDirs {
+        DataDirs {
+            dirs: dirs.iter().map(|d| d.to_path_buf()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DataDirs;
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+
+    /// The search path with only the given variables set.
+    fn resolved(flag: Option<&str>, vars: &[(&str, &str)]) -> Vec<PathBuf> {
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| OsString::from(v))
+        };
+        let dirs = DataDirs::resolve(flag.map(PathBuf::from), var);
+        dirs.iter().map(Path::to_path_buf).collect()
+    }
+
+    #[test]
+    fn prefers_the_flag_then_the_variables() {
+        let vars = [
+            ("EIJI_DATA_DIR", "/eiji"),
+            ("XDG_DATA_HOME", "/home/data"),
+            ("XDG_DATA_DIRS", "/system:/opt/share"),
+            ("HOME", "/home"),
+        ];
+        assert_eq!(resolved(Some("/flag"), &vars), [Path::new("/flag")]);
+        assert_eq!(resolved(None, &vars), [Path::new("/eiji")]);
+        assert_eq!(
+            resolved(None, &vars[1..]),
+            [
+                Path::new("/home/data/websters1913"),
+                Path::new("/system/websters1913"),
+                Path::new("/opt/share/websters1913"),
+                Path::new("."),
+            ]
+        );
+        // empty variables are unset
+        let unset = [
+            ("EIJI_DATA_DIR", ""),
+            ("XDG_DATA_HOME", ""),
+            ("HOME", "/home"),
+        ];
+        assert_eq!(
+            resolved(None, &unset),
+            [
+                Path::new("/home/.local/share/websters1913"),
+                Path::new("/usr/local/share/websters1913"),
+                Path::new("/usr/share/websters1913"),
+                Path::new("."),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_relative_system_directories() {
+        let vars = [("XDG_DATA_DIRS", "share:/usr/share:./local")];
+        assert_eq!(
+            resolved(None, &vars),
+            [Path::new("/usr/share/websters1913"), Path::new(".")]
+        );
+    }
+}
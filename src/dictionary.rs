@@ -0,0 +1,631 @@
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::data_dir::DataDirs;
+use crate::header::{self, Kind, Problem};
+use crate::hit::{Page, SearchHit};
+use crate::indexing;
+use crate::lemma::Lemmatizer;
+use crate::normalize::Folding;
+use crate::query::Query;
+use crate::rank::{by_length, rank};
+use crate::search;
+use crate::suggest::{self, Suggestion};
+
+/// Used when there is no `dictionaries.toml` in any data directory.
+const DEFAULT_REGISTRY: &str = include_str!("../dictionaries.toml");
+
+const REGISTRY_FILE: &str = "dictionaries.toml";
+
+/// Holds the `*.exc` exception lists of WordNet.
+const WORDNET_DIR: &str = "dict/WordNet-2.0";
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Registry {
+    #[serde(default)]
+    pub normalize: Folding,
+    #[serde(rename = "dictionary")]
+    pub dictionaries: Vec<Dictionary>,
+    /// With the built-in irregular forms and the WordNet exception lists of
+    /// the data directory, if any.
+    #[serde(skip, default = "Lemmatizer::builtin")]
+    pub lemmatizer: Lemmatizer,
+}
+
+impl Registry {
+    /// Reads the first `dictionaries.toml` of the data directories, or the one
+    /// built into the binary, and locates every dictionary in them. Fails
+    /// when that file cannot be read or is not a valid registry.
+    pub fn load(dirs: &DataDirs) -> Result<Registry, String> {
+        let mut registry = match dirs.find(REGISTRY_FILE) {
+            Some(dir) => {
+                let path = dir.join(REGISTRY_FILE);
+                let config = fs::read_to_string(&path)
+                    .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+                Registry::parse(&config, &path.display().to_string())?
+            }
+            None => Registry::default(),
+        };
+        for dict in registry.dictionaries.iter_mut() {
+            dict.locate(dirs);
+        }
+        if let Some(dir) = dirs.find(WORDNET_DIR) {
+            registry.lemmatizer = Lemmatizer::load(&dir.join(WORDNET_DIR));
+        }
+        Ok(registry)
+    }
+
+    fn parse(config: &str, from: &str) -> Result<Registry, String> {
+        let mut registry: Registry =
+            toml::from_str(config).map_err(|e| format!("invalid {}: {}", from, e))?;
+        for dict in &registry.dictionaries {
+            if dict.sources.len() != dict.format.sources() {
+                return Err(format!(
+                    "invalid {}: dictionary {} has {} sources but its format takes {}",
+                    from,
+                    dict.name,
+                    dict.sources.len(),
+                    dict.format.sources()
+                ));
+            }
+        }
+        registry.dictionaries.sort_by_key(|d| d.order);
+        Ok(registry)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Dictionary> {
+        self.dictionaries.iter().find(|d| d.name == name)
+    }
+
+    /// Enabled dictionaries in display order.
+    pub fn enabled(&self) -> impl Iterator<Item = &Dictionary> {
+        self.dictionaries.iter().filter(|d| d.enabled)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::parse(DEFAULT_REGISTRY, "the default registry").unwrap()
+    }
+}
+
+/// How the source files of a dictionary are turned into lines of
+/// `headword\tbody`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Tsv,
+    Parallel,
+    Tanaka,
+    Eijiro,
+    Reijiro,
+}
+
+impl Format {
+    /// How many source files a dictionary of the format is built from, the
+    /// English then the Japanese side of a parallel corpus.
+    pub fn sources(self) -> usize {
+        match self {
+            Format::Parallel => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Dictionary {
+    pub name: String,
+    pub sources: Vec<String>,
+    pub format: Format,
+    #[serde(default = "utf_8")]
+    pub encoding: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    pub order: u32,
+    files: Option<String>,
+    #[serde(default)]
+    pub auto_build: bool,
+    /// Where the generated files are, set by `locate`.
+    #[serde(skip)]
+    dir: PathBuf,
+    /// Where the sources are, usually `dir`.
+    #[serde(skip)]
+    sources_dir: PathBuf,
+}
+
+fn utf_8() -> String {
+    "utf-8".to_string()
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Whether files can be created in a directory, which is only known by
+/// trying.
+fn writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".eiji-sub-ngram-{}", std::process::id()));
+    let created = fs::File::create(&probe).is_ok();
+    if created {
+        let _ = fs::remove_file(&probe);
+    }
+    created
+}
+
+impl Dictionary {
+    /// A dictionary whose generated files are `{files}_NGRAM` and so on.
+    pub(crate) fn at(
+        files: &Path,
+        sources: Vec<String>,
+        format: Format,
+        encoding: &str,
+    ) -> Dictionary {
+        let prefix = files
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Dictionary {
+            name: prefix.to_lowercase(),
+            sources,
+            format,
+            encoding: encoding.to_string(),
+            enabled: true,
+            order: 0,
+            files: Some(prefix),
+            auto_build: false,
+            dir: files.parent().unwrap_or(Path::new("")).to_path_buf(),
+            sources_dir: files.parent().unwrap_or(Path::new("")).to_path_buf(),
+        }
+    }
+
+    /// Opens the index files `{files}_NGRAM`, `{files}_INDEX`,
+    /// `{files}_HEADWORD` and `{files}_TEXT`, such as `data/EDICT`.
+    pub fn open(files: impl AsRef<Path>) -> Result<Dictionary, Problem> {
+        let dict = Dictionary::at(files.as_ref(), vec![], Format::Tsv, &utf_8());
+        dict.verify(Folding::None)?;
+        Ok(dict)
+    }
+
+    fn file_name(&self, suffix: &str) -> String {
+        match &self.files {
+            Some(prefix) => format!("{}_{}", prefix, suffix),
+            None => format!("{}_{}", self.name.to_uppercase(), suffix),
+        }
+    }
+
+    fn file(&self, suffix: &str) -> String {
+        self.dir.join(self.file_name(suffix)).display().to_string()
+    }
+
+    /// Picks the first data directory holding either the generated text or
+    /// all the sources, or the first one when there is neither. The sources
+    /// are resolved against the first directory holding them all. When that
+    /// directory cannot be written, such as a system directory, the files are
+    /// generated in the first data directory instead.
+    fn locate(&mut self, dirs: &DataDirs) {
+        let text = self.file_name("TEXT");
+        let has_sources = |dir: &Path| self.sources.iter().all(|s| dir.join(s).exists());
+        let found = dirs
+            .iter()
+            .find(|dir| dir.join(&text).exists() || has_sources(dir));
+        let sources_dir = dirs.iter().find(|dir| has_sources(dir));
+        self.dir = match found {
+            Some(dir) if dir.join(&text).exists() || writable(dir) => dir,
+            Some(dir) => {
+                info!(
+                    "{} is not writable, {} goes to {}",
+                    dir.display(),
+                    text,
+                    dirs.first().display()
+                );
+                dirs.first()
+            }
+            None => dirs.first(),
+        }
+        .to_path_buf();
+        self.sources_dir = sources_dir.unwrap_or(&self.dir).to_path_buf();
+        self.sources = self
+            .sources
+            .iter()
+            .map(|s| self.sources_dir.join(s).display().to_string())
+            .collect();
+    }
+
+    /// The directory the generated files go to, when it is not the one of
+    /// the sources.
+    pub fn relocated(&self) -> Option<&Path> {
+        (self.sources_dir != self.dir).then_some(self.dir.as_path())
+    }
+
+    pub fn ngram_file(&self) -> String {
+        self.file("NGRAM")
+    }
+
+    pub fn index_file(&self) -> String {
+        self.file("INDEX")
+    }
+
+    pub fn headword_file(&self) -> String {
+        self.file("HEADWORD")
+    }
+
+    pub fn text_file(&self) -> String {
+        self.file("TEXT")
+    }
+
+    pub fn norm_ngram_file(&self) -> String {
+        self.file("NORM_NGRAM")
+    }
+
+    pub fn norm_index_file(&self) -> String {
+        self.file("NORM_INDEX")
+    }
+
+    pub fn sources_exist(&self) -> bool {
+        !self.sources.is_empty() && self.sources.iter().all(|s| Path::new(s).exists())
+    }
+
+    /// The generated files, including the normalized index unless `folding`
+    /// is `Folding::None`.
+    pub fn files(&self, folding: Folding) -> Vec<(String, Kind)> {
+        let mut files = vec![
+            (self.ngram_file(), Kind::Ngram),
+            (self.index_file(), Kind::Index),
+            (self.headword_file(), Kind::Headword),
+            (self.text_file(), Kind::Text),
+        ];
+        if folding != Folding::None {
+            files.push((self.norm_ngram_file(), Kind::NormalizedNgram));
+            files.push((self.norm_index_file(), Kind::NormalizedIndex));
+        }
+        files
+    }
+
+    /// Checks the generated files, the normalized index too unless `folding`
+    /// is `Folding::None`.
+    pub fn verify(&self, folding: Folding) -> Result<(), Problem> {
+        header::verify(&self.files(folding), &self.sources, folding)
+    }
+
+    /// Builds the index from the sources, the normalized index too unless
+    /// `folding` is `Folding::None`.
+    pub fn build(&self, folding: Folding) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        indexing::setup(self, folding)
+    }
+
+    /// The matching lines, as stored in the text file, ranked as `search`
+    /// ranks them before the page is cut. An invalid regular expression
+    /// matches nothing, `SearchOptions::query` tells why. So do generated
+    /// files which cannot be opened, the error is logged and `verify` tells
+    /// what is wrong with them.
+    pub fn find(&self, query: &str, options: &SearchOptions) -> Vec<String> {
+        self.search(query, options)
+            .iter()
+            .map(SearchHit::line)
+            .collect()
+    }
+
+    /// Like `find`, keeping where every line is and which parts of it match.
+    /// Never fails, missing files give no hits as they do for `find`.
+    pub fn search(&self, query: &str, options: &SearchOptions) -> Vec<SearchHit> {
+        self.search_page(query, options).hits
+    }
+
+    /// Like `search`, also telling how many hits there are in all.
+    pub fn search_page(&self, query: &str, options: &SearchOptions) -> Page {
+        let Ok(query) = options.query(query) else {
+            return Page::new(vec![], options.offset, options.limit);
+        };
+        let mut hits = self
+            .matching(&query, options)
+            .into_iter()
+            .map(|(offset, line)| {
+                SearchHit::new(&self.name, offset, &line, &query, options.folding)
+            })
+            .collect::<Vec<SearchHit>>();
+        options.sort(&mut hits);
+        Page::new(hits, options.offset, options.limit)
+    }
+
+    /// Whether the word is a headword of the dictionary as it is.
+    pub fn has_headword(&self, word: &str) -> bool {
+        !self.headword_lines(word).is_empty()
+    }
+
+    /// The hits of the lines whose headword is the word as it is, ranked.
+    pub fn headword_search(&self, word: &str, options: &SearchOptions) -> Vec<SearchHit> {
+        let Ok(query) = options.query(word) else {
+            return vec![];
+        };
+        let mut hits = self
+            .headword_lines(word)
+            .into_iter()
+            .map(|(offset, line)| {
+                SearchHit::new(&self.name, offset, &line, &query, options.folding)
+            })
+            .collect::<Vec<SearchHit>>();
+        options.sort(&mut hits);
+        hits
+    }
+
+    /// The lines whose headword is the word, with their offsets.
+    fn headword_lines(&self, word: &str) -> Vec<(u64, String)> {
+        let nums = search::prefix_search(word, &self.headword_file(), &self.text_file());
+        let headword = format!("{}\t", word);
+        search::filter_lines(&nums, &self.text_file(), |l| l.starts_with(&headword))
+    }
+
+    /// Headwords close to a query which found nothing, such as `isomorphism`
+    /// for `isomorhpism`, the closest first.
+    pub fn suggest(&self, query: &str, limit: usize) -> Vec<Suggestion> {
+        let mut found = suggest::suggest(
+            query,
+            &self.ngram_file(),
+            &self.index_file(),
+            &self.text_file(),
+        );
+        found.truncate(limit);
+        found
+    }
+
+    /// The lines matching the query with their offsets. The records of every
+    /// alternative are those found for all its terms, which are then checked
+    /// against the whole query.
+    fn matching(&self, query: &Query, options: &SearchOptions) -> Vec<(u64, String)> {
+        if query.is_regex() {
+            return self.matching_regex(query);
+        }
+        let mut nums = vec![];
+        for terms in &query.alternatives {
+            let mut found: Option<Vec<(u64, u32)>> = None;
+            for term in terms.iter().filter(|t| !t.negated) {
+                let mut records = self.records(&term.text, options);
+                records.sort();
+                found = Some(match found {
+                    None => records,
+                    Some(mut found) => {
+                        found.retain(|r| records.binary_search(r).is_ok());
+                        found
+                    }
+                });
+            }
+            nums.extend(found.unwrap_or_default());
+        }
+        nums.sort();
+        nums.dedup();
+        search::filter_query(query, &nums, &self.text_file(), options.folding)
+    }
+
+    /// The lines matching a regular expression, among those containing its
+    /// rarest literal fragment, or among all of them when it has none. Those
+    /// of a wildcard pattern with a literal start are found by headword.
+    fn matching_regex(&self, query: &Query) -> Vec<(u64, String)> {
+        if !query.head().is_empty() {
+            let mut nums =
+                search::prefix_search(query.head(), &self.headword_file(), &self.text_file());
+            nums.sort();
+            return search::filter_query(query, &nums, &self.text_file(), Folding::None);
+        }
+        let rarest = query
+            .fragments()
+            .iter()
+            .min_by_key(|f| search::ngram_count(f, &self.ngram_file()));
+        let nums = match rarest {
+            Some(fragment) => {
+                search::ngram_search(fragment, &self.ngram_file(), &self.index_file())
+            }
+            None => {
+                info!("no literal to look up, scanning all of {}", self.name);
+                search::all_records(&self.headword_file())
+            }
+        };
+        search::filter_query(query, &nums, &self.text_file(), Folding::None)
+    }
+
+    fn records(&self, query: &str, options: &SearchOptions) -> Vec<(u64, u32)> {
+        if options.prefix {
+            search::prefix_search(query, &self.headword_file(), &self.text_file())
+        } else if options.folding != Folding::None {
+            search::normalized_search(query, &self.norm_ngram_file(), &self.norm_index_file())
+        } else {
+            search::ngram_search(&query.to_string(), &self.ngram_file(), &self.index_file())
+        }
+    }
+}
+
+/// How `Dictionary::search` looks for a query.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SearchOptions {
+    /// Searches headwords starting with the query instead of the full text,
+    /// always case and width sensitive since the headwords are sorted as
+    /// they are: `folding` makes no difference.
+    pub prefix: bool,
+    /// Takes the query as a regular expression, matched against the lines
+    /// as they are whatever the folding.
+    pub regex: bool,
+    /// Takes the query as a headword pattern of `?` and `*` wildcards, and
+    /// orders the hits by headword length instead of relevance.
+    pub wildcard: bool,
+    /// Searches the normalized index folded this way, unless `Folding::None`.
+    pub folding: Folding,
+    /// Skips this many of the ranked hits.
+    pub offset: usize,
+    /// At most this many hits.
+    pub limit: Option<usize>,
+}
+
+impl SearchOptions {
+    /// The query to search with these options: boolean unless searching
+    /// headwords by prefix, which takes the input as it is, or by regular
+    /// expression, which may be invalid.
+    pub fn query(&self, input: &str) -> Result<Query, regex::Error> {
+        if self.regex {
+            Query::regex(input)
+        } else if self.wildcard {
+            Ok(Query::wildcard(input))
+        } else if self.prefix {
+            Ok(Query::literal(input))
+        } else {
+            Ok(Query::parse(input))
+        }
+    }
+
+    /// Sorts the hits of a query searched with these options: by relevance,
+    /// or by headword length for a wildcard pattern.
+    pub fn sort(&self, hits: &mut [SearchHit]) {
+        if self.wildcard {
+            by_length(hits);
+        } else {
+            rank(hits);
+        }
+    }
+
+    /// Options for a query as typed by the user, where a leading `/` asks for
+    /// a regular expression, a leading `~` for a wildcard pattern such as
+    /// `~c?t` and a trailing `*` for a headword prefix search. Only the full
+    /// text search is folded, `cat*` does not find `Cat`. Returns the query
+    /// without the `/`, the `~` or the `*`. A `?` elsewhere is searched as it
+    /// is.
+    pub fn for_query(query: &str, folding: Folding) -> (&str, SearchOptions) {
+        if let Some(pattern) = query.strip_prefix('/').filter(|p| !p.is_empty()) {
+            return (
+                pattern,
+                SearchOptions {
+                    regex: true,
+                    ..Default::default()
+                },
+            );
+        }
+        if let Some(pattern) = query.strip_prefix('~').filter(|p| Query::is_wildcard(p)) {
+            return (
+                pattern,
+                SearchOptions {
+                    wildcard: true,
+                    ..Default::default()
+                },
+            );
+        }
+        match query.strip_suffix('*') {
+            Some(head) if !head.is_empty() => (
+                head,
+                SearchOptions {
+                    prefix: true,
+                    ..Default::default()
+                },
+            ),
+            _ => (
+                query,
+                SearchOptions {
+                    folding,
+                    ..Default::default()
+                },
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Registry, SearchOptions};
+    use crate::data_dir::DataDirs;
+    use crate::normalize::Folding;
+    use crate::scratch::Scratch;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn reports_an_invalid_registry() {
+        let scratch = Scratch::new("registry");
+        let dir = scratch.dir().to_path_buf();
+        fs::write(
+            dir.join("dictionaries.toml"),
+            "[[dictionary]]\nname = \"typo\"\nsorces = []\n",
+        )
+        .unwrap();
+        let e = Registry::load(&DataDirs::of(&[&dir])).unwrap_err();
+        assert!(e.starts_with(&format!(
+            "invalid {}",
+            dir.join("dictionaries.toml").display()
+        )));
+        assert!(e.contains("sorces"));
+        assert!(Registry::parse("normalize = \"nfkc\"", "a registry").is_err());
+        assert!(Registry::parse("normalize = \"nfkc\"\ndictionary = []", "a registry").is_ok());
+        let e = Registry::parse(
+            "[[dictionary]]\nname = \"half\"\nsources = [\"en.txt\"]\nformat = \"parallel\"\norder = 1\n",
+            "a registry",
+        )
+        .unwrap_err();
+        assert_eq!(
+            e,
+            "invalid a registry: dictionary half has 1 sources but its format takes 2"
+        );
+    }
+
+    #[test]
+    fn folds_only_the_full_text_search() {
+        let (query, options) = SearchOptions::for_query("Cat*", Folding::Kana);
+        assert_eq!((query, options.prefix), ("Cat", true));
+        assert_eq!(options.folding, Folding::None);
+        let (query, options) = SearchOptions::for_query("Cat", Folding::Kana);
+        assert_eq!((query, options.folding), ("Cat", Folding::Kana));
+        for pattern in ["/^cat", "~c?t"] {
+            let (_, options) = SearchOptions::for_query(pattern, Folding::Kana);
+            assert_eq!(options.folding, Folding::None);
+        }
+    }
+
+    #[test]
+    fn locates_the_files_in_the_first_directory_holding_them() {
+        let (first, second) = (Scratch::new("first"), Scratch::new("second"));
+        let registry = Registry::parse(
+            "[[dictionary]]\nname = \"cats\"\nsources = [\"cats.tab\"]\nformat = \"tsv\"\norder = 1\n",
+            "a registry",
+        )
+        .unwrap();
+        let located = |dirs: &[&Path]| {
+            let mut dict = registry.get("cats").unwrap().clone();
+            dict.locate(&DataDirs::of(dirs));
+            dict
+        };
+        let dirs = [first.dir(), second.dir()];
+
+        let dict = located(&dirs);
+        assert_eq!(dict.text_file(), first.file("CATS_TEXT"));
+        assert_eq!(dict.sources, [first.file("cats.tab")]);
+
+        fs::write(second.file("cats.tab"), "cat\t猫\n").unwrap();
+        let dict = located(&dirs);
+        assert_eq!(dict.text_file(), second.file("CATS_TEXT"));
+        assert_eq!(dict.sources, [second.file("cats.tab")]);
+        assert_eq!(dict.relocated(), None);
+
+        // generated files already there win over the sources
+        fs::write(first.file("CATS_TEXT"), "").unwrap();
+        let dict = located(&dirs);
+        assert_eq!(dict.text_file(), first.file("CATS_TEXT"));
+        assert_eq!(dict.sources, [second.file("cats.tab")]);
+        assert_eq!(dict.relocated(), Some(first.dir()));
+    }
+
+    /// Not even root can create files in procfs.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn generates_the_files_elsewhere_when_the_sources_are_read_only() {
+        let scratch = Scratch::new("writable");
+        let registry = Registry::parse(
+            "[[dictionary]]\nname = \"proc\"\nsources = [\"version\"]\nformat = \"tsv\"\norder = 1\n",
+            "a registry",
+        )
+        .unwrap();
+        let mut dict = registry.get("proc").unwrap().clone();
+        dict.locate(&DataDirs::of(&[scratch.dir(), Path::new("/proc")]));
+        assert_eq!(dict.text_file(), scratch.file("PROC_TEXT"));
+        assert_eq!(dict.sources, ["/proc/version"]);
+        assert_eq!(dict.relocated(), Some(scratch.dir()));
+    }
+}
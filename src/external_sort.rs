@@ -0,0 +1,235 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+
+/// An n-gram with the offset and length of the line it was taken from.
+pub type Segment = ([u8; 12], u64, u32);
+
+const RUN_RECORD: usize = 12 + 8 + 4;
+
+const DEFAULT_MEMORY_MB: usize = 1024;
+
+/// Memory budget of the index builder in bytes, 1 GiB unless
+/// `EIJI_INDEX_MEMORY_MB` says otherwise.
+pub fn memory_budget() -> usize {
+    std::env::var("EIJI_INDEX_MEMORY_MB")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MEMORY_MB)
+        * 1024
+        * 1024
+}
+
+/// Sorts and deduplicates segments, spilling sorted runs into temporary files
+/// next to `prefix` whenever the buffered segments exceed the memory budget.
+/// The runs are removed when the sorter is dropped, finished or not.
+pub struct ExternalSorter {
+    prefix: String,
+    capacity: usize,
+    buffer: Vec<Segment>,
+    runs: Vec<String>,
+    sorting: Duration,
+}
+
+impl ExternalSorter {
+    /// `expected` is an upper bound of the number of segments, used to avoid
+    /// reserving the whole budget for small dictionaries.
+    pub fn new(prefix: &str, memory_budget: usize, expected: usize) -> ExternalSorter {
+        let capacity = (memory_budget / mem::size_of::<Segment>()).max(1);
+        ExternalSorter {
+            prefix: prefix.to_string(),
+            capacity,
+            buffer: Vec::with_capacity(capacity.min(expected)),
+            runs: vec![],
+            sorting: Duration::ZERO,
+        }
+    }
+
+    /// Number of segments held in memory before a run is spilled.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Time spent sorting and spilling runs so far.
+    pub fn sorting(&self) -> Duration {
+        self.sorting
+    }
+
+    /// Adds `count` segments, spilling the buffered ones first if they would
+    /// not fit.
+    pub fn extend(
+        &mut self,
+        segments: impl ParallelIterator<Item = Segment>,
+        count: usize,
+    ) -> io::Result<()> {
+        if !self.buffer.is_empty() && self.buffer.len() + count > self.capacity {
+            self.spill()?;
+        }
+        self.buffer.par_extend(segments);
+        Ok(())
+    }
+
+    fn sort(&mut self) {
+        let started = Instant::now();
+        self.buffer.par_sort_unstable();
+        self.buffer.dedup();
+        self.sorting += started.elapsed();
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        self.sort();
+        let started = Instant::now();
+        let run = format!("{}.run{}", self.prefix, self.runs.len());
+        info!("writing {} segments into {}", self.buffer.len(), run);
+        let mut runf = BufWriter::new(fs::File::create(&run)?);
+        self.runs.push(run);
+        for (gram, offset, len) in self.buffer.drain(..) {
+            runf.write_all(&gram)?;
+            runf.write_all(&offset.to_be_bytes())?;
+            runf.write_all(&len.to_be_bytes())?;
+        }
+        runf.flush()?;
+        self.sorting += started.elapsed();
+        Ok(())
+    }
+
+    /// Hands every distinct segment to `emit` in ascending order and tells
+    /// how long sorting took altogether. Stops at the first error of `emit`.
+    pub fn finish(
+        mut self,
+        mut emit: impl FnMut(&Segment) -> io::Result<()>,
+    ) -> io::Result<Duration> {
+        if self.runs.is_empty() {
+            self.sort();
+            self.buffer.iter().try_for_each(emit)?;
+            return Ok(self.sorting);
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        let mut readers = self
+            .runs
+            .iter()
+            .map(|run| fs::File::open(run).map(BufReader::new))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut heap = BinaryHeap::with_capacity(readers.len());
+        for (i, reader) in readers.iter_mut().enumerate() {
+            if let Some(segment) = read_segment(reader) {
+                heap.push(Reverse((segment, i)));
+            }
+        }
+        let mut last = None;
+        while let Some(Reverse((segment, i))) = heap.pop() {
+            if last != Some(segment) {
+                emit(&segment)?;
+                last = Some(segment);
+            }
+            if let Some(next) = read_segment(&mut readers[i]) {
+                heap.push(Reverse((next, i)));
+            }
+        }
+        Ok(self.sorting)
+    }
+}
+
+impl Drop for ExternalSorter {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            if let Err(e) = fs::remove_file(run) {
+                warn!("{}: {}", run, e);
+            }
+        }
+    }
+}
+
+fn read_segment(reader: &mut impl Read) -> Option<Segment> {
+    let mut b = [0u8; RUN_RECORD];
+    reader.read_exact(&mut b).ok()?;
+    Some((
+        b[0..12].try_into().unwrap(),
+        u64::from_be_bytes(b[12..20].try_into().unwrap()),
+        u32::from_be_bytes(b[20..24].try_into().unwrap()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExternalSorter, Segment};
+    use crate::scratch::Scratch;
+    use rayon::prelude::*;
+    use std::mem;
+
+    #[test]
+    fn merges_spilled_runs_into_the_in_memory_order() {
+        let segment = |i: u64| {
+            let mut gram = [0u8; 12];
+            gram[..3].copy_from_slice(&[b'a' + (i * 7 % 5) as u8, b'b', (i % 3) as u8]);
+            (gram, i * 31 % 17, (i % 4) as u32)
+        };
+        // every batch repeats segments of the others
+        let batches = (0..6u64)
+            .map(|b| (b * 5..b * 5 + 12).map(segment).collect::<Vec<Segment>>())
+            .collect::<Vec<_>>();
+        let mut expected = batches.concat();
+        expected.sort_unstable();
+        expected.dedup();
+
+        let scratch = Scratch::new("sort");
+        let prefix = &scratch.file("SORTED_NGRAM");
+        let mut sorter = ExternalSorter::new(prefix, 16 * mem::size_of::<Segment>(), 64);
+        assert_eq!(sorter.capacity(), 16);
+        for batch in &batches {
+            sorter
+                .extend(batch.clone().into_par_iter(), batch.len())
+                .unwrap();
+        }
+        assert!(sorter.runs.len() > 2);
+        let runs = sorter.runs.clone();
+        let mut merged = vec![];
+        sorter
+            .finish(|s| {
+                merged.push(*s);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(merged, expected);
+
+        let mut in_memory = ExternalSorter::new(prefix, 1 << 20, 64);
+        for batch in &batches {
+            in_memory
+                .extend(batch.clone().into_par_iter(), batch.len())
+                .unwrap();
+        }
+        assert!(in_memory.runs.is_empty());
+        let mut sorted = vec![];
+        in_memory
+            .finish(|s| {
+                sorted.push(*s);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(sorted, merged);
+        assert!(runs.iter().all(|run| std::fs::metadata(run).is_err()));
+    }
+
+    #[test]
+    fn removes_the_runs_of_an_unfinished_sort() {
+        let scratch = Scratch::new("unfinished");
+        let mut sorter = ExternalSorter::new(&scratch.file("NGRAM"), mem::size_of::<Segment>(), 4);
+        for i in 0..3u8 {
+            let segment = ([i; 12], i as u64, 1);
+            sorter.extend(vec![segment].into_par_iter(), 1).unwrap();
+        }
+        let runs = sorter.runs.clone();
+        assert_eq!(runs.len(), 2);
+        assert!(runs.iter().all(|run| std::fs::metadata(run).is_ok()));
+        drop(sorter);
+        assert!(runs.iter().all(|run| std::fs::metadata(run).is_err()));
+    }
+}
@@ -0,0 +1,393 @@
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::time::UNIX_EPOCH;
+
+use crate::normalize::Folding;
+
+/// Every generated file starts with a header of this size. Offsets stored in
+/// the index files are relative to the end of the header.
+pub const HEADER_LEN: u64 = 64;
+
+const MAGIC: &[u8; 8] = b"EIJINGRM";
+
+/// Bumped whenever the layout of any generated file changes.
+pub const FORMAT_VERSION: u16 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Ngram = 1,
+    Index = 2,
+    Headword = 3,
+    Text = 4,
+    /// Built from normalized text but pointing to the original lines.
+    NormalizedNgram = 5,
+    NormalizedIndex = 6,
+}
+
+impl Kind {
+    fn record_size(self, offsets: Offsets) -> u32 {
+        match self {
+            Kind::Ngram | Kind::NormalizedNgram => 12,
+            Kind::Index | Kind::NormalizedIndex | Kind::Headword => offsets.record_size() as u32,
+            Kind::Text => 1,
+        }
+    }
+
+    fn is_normalized(self) -> bool {
+        matches!(self, Kind::NormalizedNgram | Kind::NormalizedIndex)
+    }
+}
+
+/// Reads how the text of a normalized index was folded from its header.
+pub fn folding(header: &[u8]) -> Folding {
+    Folding::of(header[40])
+}
+
+/// Width of the line offsets in the index and headword records. Each record
+/// is a big-endian offset followed by a big-endian u32 line length.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Offsets {
+    Narrow = 4,
+    Wide = 8,
+}
+
+impl Offsets {
+    /// u64 offsets are only used when the text outgrows u32.
+    pub fn for_text(len: u64) -> Offsets {
+        if len > u32::MAX as u64 {
+            Offsets::Wide
+        } else {
+            Offsets::Narrow
+        }
+    }
+
+    pub fn record_size(self) -> usize {
+        self as usize + 4
+    }
+
+    /// Reads the offsets width from the header of a generated file.
+    pub fn of(header: &[u8]) -> Offsets {
+        match header[11] {
+            8 => Offsets::Wide,
+            _ => Offsets::Narrow,
+        }
+    }
+}
+
+/// Identifies the source files an index was built from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub mtime: u64,
+    pub hash: u64,
+}
+
+impl Fingerprint {
+    /// Size, latest modification time and FNV-1a hash over all the sources.
+    pub fn of(sources: &[String]) -> Fingerprint {
+        let mut fingerprint = Fingerprint {
+            hash: FNV_OFFSET,
+            ..Default::default()
+        };
+        for source in sources {
+            let (size, mtime) = stat(source);
+            fingerprint.size += size;
+            fingerprint.mtime = fingerprint.mtime.max(mtime);
+            let mut f = File::open(source).unwrap();
+            let mut chunk = vec![0u8; CHUNK];
+            loop {
+                let n = f.read(&mut chunk).unwrap();
+                if n == 0 {
+                    break;
+                }
+                fingerprint.hash = fnv1a(fingerprint.hash, &chunk[..n]);
+            }
+        }
+        fingerprint
+    }
+
+    /// Compares against the sources, hashing them only when size matches but
+    /// the modification time does not. Returns the latest modification time
+    /// of the sources when they match.
+    fn matches(&self, sources: &[String]) -> Option<u64> {
+        let (mut size, mut mtime) = (0, 0);
+        for source in sources {
+            let (s, m) = stat(source);
+            size += s;
+            mtime = m.max(mtime);
+        }
+        if size != self.size {
+            return None;
+        }
+        (mtime == self.mtime || Fingerprint::of(sources).hash == self.hash).then_some(mtime)
+    }
+}
+
+/// Sources are hashed this many bytes at a time.
+const CHUNK: usize = 1 << 16;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn stat(source: &str) -> (u64, u64) {
+    let meta = fs::metadata(source).unwrap();
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    (meta.len(), mtime)
+}
+
+pub fn encode(
+    kind: Kind,
+    offsets: Offsets,
+    folding: Folding,
+    fingerprint: &Fingerprint,
+) -> [u8; HEADER_LEN as usize] {
+    let mut h = [0u8; HEADER_LEN as usize];
+    h[0..8].copy_from_slice(MAGIC);
+    h[8..10].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    h[10] = kind as u8;
+    h[11] = offsets as u8;
+    h[12..16].copy_from_slice(&kind.record_size(offsets).to_be_bytes());
+    h[16..24].copy_from_slice(&fingerprint.size.to_be_bytes());
+    h[24..32].copy_from_slice(&fingerprint.mtime.to_be_bytes());
+    h[32..40].copy_from_slice(&fingerprint.hash.to_be_bytes());
+    h[40] = folding as u8;
+    h
+}
+
+/// Why a generated file cannot be used as it is.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    Missing(String),
+    Foreign(String),
+    Outdated(String, u16),
+    Stale(String),
+    Refolded(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Missing(file) => write!(f, "{} does not exist", file),
+            Problem::Foreign(file) => write!(f, "{} is not an index file of this program", file),
+            Problem::Outdated(file, version) => write!(
+                f,
+                "{} has format version {} but version {} is required",
+                file, version, FORMAT_VERSION
+            ),
+            Problem::Stale(file) => write!(f, "{} was built from a different source file", file),
+            Problem::Refolded(file) => write!(f, "{} was normalized with other settings", file),
+        }
+    }
+}
+
+fn read(file: &str, kind: Kind, folding: Folding) -> Result<Fingerprint, Problem> {
+    let mut h = [0u8; HEADER_LEN as usize];
+    match File::open(file) {
+        Ok(mut f) => {
+            if f.read_exact(&mut h).is_err() || &h[0..8] != MAGIC {
+                return Err(Problem::Foreign(file.to_string()));
+            }
+        }
+        Err(_) => return Err(Problem::Missing(file.to_string())),
+    }
+    let version = u16::from_be_bytes([h[8], h[9]]);
+    if version != FORMAT_VERSION {
+        return Err(Problem::Outdated(file.to_string(), version));
+    }
+    let record_size = u32::from_be_bytes(h[12..16].try_into().unwrap());
+    let offsets = Offsets::of(&h);
+    if h[10] != kind as u8 || h[11] != offsets as u8 || record_size != kind.record_size(offsets) {
+        return Err(Problem::Foreign(file.to_string()));
+    }
+    if kind.is_normalized() && self::folding(&h) != folding {
+        return Err(Problem::Refolded(file.to_string()));
+    }
+    let num = |r: std::ops::Range<usize>| u64::from_be_bytes(h[r].try_into().unwrap());
+    Ok(Fingerprint {
+        size: num(16..24),
+        mtime: num(24..32),
+        hash: num(32..40),
+    })
+}
+
+/// Checks that every generated file has a valid header and that they were all
+/// built from the given sources, normalized indices with the given folding.
+/// When the sources are absent or unknown, the files are only checked against
+/// each other.
+pub fn verify(
+    files: &[(String, Kind)],
+    sources: &[String],
+    folding: Folding,
+) -> Result<(), Problem> {
+    let mut built_from = None;
+    for (file, kind) in files {
+        let fingerprint = read(file, *kind, folding)?;
+        if *built_from.get_or_insert(fingerprint) != fingerprint {
+            return Err(Problem::Stale(file.clone()));
+        }
+    }
+    let sources_exist = !sources.is_empty() && sources.iter().all(|s| fs::metadata(s).is_ok());
+    match built_from {
+        Some(fingerprint) if sources_exist => match fingerprint.matches(sources) {
+            None => Err(Problem::Stale(files[0].0.clone())),
+            Some(mtime) => {
+                if mtime != fingerprint.mtime {
+                    restamp(files, mtime);
+                }
+                Ok(())
+            }
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Records a new modification time of unchanged sources in the headers, so
+/// that they are not hashed again next time. Files which cannot be written
+/// are left as they are.
+fn restamp(files: &[(String, Kind)], mtime: u64) {
+    for (file, _) in files {
+        let written = OpenOptions::new().write(true).open(file).and_then(|mut f| {
+            f.seek(SeekFrom::Start(24))?;
+            f.write_all(&mtime.to_be_bytes())
+        });
+        if let Err(e) = written {
+            info!("cannot update the header of {}: {}", file, e);
+        }
+    }
+}
+
+/// Number of records in a generated file, or `None` when it cannot be read.
+pub fn records(file: &str) -> Option<u64> {
+    let mut h = [0u8; HEADER_LEN as usize];
+    let mut f = File::open(file).ok()?;
+    f.read_exact(&mut h).ok()?;
+    let record_size = u32::from_be_bytes(h[12..16].try_into().unwrap()).max(1) as u64;
+    let len = f.metadata().ok()?.len();
+    Some(len.saturating_sub(HEADER_LEN) / record_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, verify, Fingerprint, Kind, Offsets, Problem, FORMAT_VERSION};
+    use crate::normalize::Folding;
+    use crate::scratch::Scratch;
+    use std::fs;
+
+    /// A source and a generated file built from it, in a fresh directory.
+    fn built(name: &str, kind: Kind, folding: Folding) -> (Scratch, String, String) {
+        let scratch = Scratch::new(&format!("header-{}", name));
+        let source = scratch.file("source.tab");
+        let file = scratch.file("GENERATED");
+        fs::write(&source, "isomorphism\t同型\n").unwrap();
+        let fingerprint = Fingerprint::of(std::slice::from_ref(&source));
+        fs::write(&file, encode(kind, Offsets::Narrow, folding, &fingerprint)).unwrap();
+        (scratch, source, file)
+    }
+
+    #[test]
+    fn widens_offsets_past_four_gibibytes() {
+        assert_eq!(Offsets::for_text(0), Offsets::Narrow);
+        assert_eq!(Offsets::for_text(u32::MAX as u64), Offsets::Narrow);
+        assert_eq!(Offsets::for_text(u32::MAX as u64 + 1), Offsets::Wide);
+        assert_eq!(Offsets::Narrow.record_size(), 8);
+        assert_eq!(Offsets::Wide.record_size(), 12);
+        let h = encode(
+            Kind::Index,
+            Offsets::Wide,
+            Folding::None,
+            &Fingerprint::default(),
+        );
+        assert_eq!(Offsets::of(&h), Offsets::Wide);
+    }
+
+    #[test]
+    fn accepts_files_built_from_the_sources() {
+        let (_dir, source, file) = built("valid", Kind::Ngram, Folding::None);
+        assert_eq!(
+            verify(&[(file, Kind::Ngram)], &[source], Folding::None),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn tells_why_a_file_cannot_be_used() {
+        let (_dir, source, file) = built("problems", Kind::Index, Folding::None);
+        let sources = [source.clone()];
+        let check = |kind| verify(&[(file.clone(), kind)], &sources, Folding::None);
+        assert_eq!(check(Kind::Headword), Err(Problem::Foreign(file.clone())));
+
+        let missing = format!("{}_MISSING", file);
+        assert_eq!(
+            verify(&[(missing.clone(), Kind::Index)], &sources, Folding::None),
+            Err(Problem::Missing(missing))
+        );
+
+        let mut header = fs::read(&file).unwrap();
+        header[8..10].copy_from_slice(&(FORMAT_VERSION - 1).to_be_bytes());
+        fs::write(&file, &header).unwrap();
+        assert_eq!(
+            check(Kind::Index),
+            Err(Problem::Outdated(file.clone(), FORMAT_VERSION - 1))
+        );
+
+        header[0..8].copy_from_slice(b"NOTMAGIC");
+        fs::write(&file, &header).unwrap();
+        assert_eq!(check(Kind::Index), Err(Problem::Foreign(file.clone())));
+        fs::write(&file, b"short").unwrap();
+        assert_eq!(check(Kind::Index), Err(Problem::Foreign(file.clone())));
+
+        let (_dir, source, other) = built("stale", Kind::Index, Folding::None);
+        // same size, told apart by the hash as the build was earlier
+        fs::write(&source, "isomorphism\t同形\n").unwrap();
+        let mut header = fs::read(&other).unwrap();
+        header[24..32].copy_from_slice(&1u64.to_be_bytes());
+        fs::write(&other, &header).unwrap();
+        assert_eq!(
+            verify(&[(other.clone(), Kind::Index)], &[source], Folding::None),
+            Err(Problem::Stale(other))
+        );
+    }
+
+    #[test]
+    fn rejects_an_index_normalized_otherwise() {
+        let (_dir, source, file) = built("refolded", Kind::NormalizedNgram, Folding::Nfkc);
+        let files = [(file.clone(), Kind::NormalizedNgram)];
+        let sources = [source];
+        assert_eq!(verify(&files, &sources, Folding::Nfkc), Ok(()));
+        assert_eq!(
+            verify(&files, &sources, Folding::Kana),
+            Err(Problem::Refolded(file))
+        );
+    }
+
+    #[test]
+    fn restamps_sources_touched_but_unchanged() {
+        let (_dir, source, file) = built("touched", Kind::Text, Folding::None);
+        let mut header = fs::read(&file).unwrap();
+        header[24..32].copy_from_slice(&1u64.to_be_bytes());
+        fs::write(&file, &header).unwrap();
+        let files = [(file.clone(), Kind::Text)];
+        assert_eq!(
+            verify(&files, std::slice::from_ref(&source), Folding::None),
+            Ok(())
+        );
+        let restamped = fs::read(&file).unwrap();
+        let mtime = Fingerprint::of(&[source]).mtime;
+        assert_eq!(restamped[24..32], mtime.to_be_bytes());
+    }
+}
@@ -0,0 +1,138 @@
+use serde::Serialize;
+
+use crate::normalize::Folding;
+use crate::query::Query;
+
+/// A line of a dictionary matching a query, split into headword and body.
+/// Spans are byte ranges of the headword or the body that matched.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit {
+    pub dictionary: String,
+    pub headword: String,
+    pub body: String,
+    /// Offset of the line in the text file of the dictionary.
+    pub offset: u64,
+    pub headword_spans: Vec<(usize, usize)>,
+    pub body_spans: Vec<(usize, usize)>,
+    /// The base form of the query the hit was found via, if not the query.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lemma: Option<String>,
+}
+
+impl SearchHit {
+    /// The hit of a line, with the spans of what the query matched.
+    pub fn new(
+        dictionary: &str,
+        offset: u64,
+        line: &str,
+        query: &Query,
+        folding: Folding,
+    ) -> SearchHit {
+        let (headword, body) = line.split_once('\t').unwrap_or((line, ""));
+        // matched on the whole line, so that a match may span the tab
+        let spans = query.spans(line, folding);
+        SearchHit {
+            dictionary: dictionary.to_string(),
+            headword: headword.to_string(),
+            body: body.to_string(),
+            offset,
+            headword_spans: split_spans(&spans, 0, headword.len()),
+            // a line without a tab has no body
+            body_spans: split_spans(&spans, (headword.len() + 1).min(line.len()), line.len()),
+            lemma: None,
+        }
+    }
+
+    /// The line as stored in the text file, `headword<TAB>body`.
+    pub fn line(&self) -> String {
+        format!("{}\t{}", self.headword, self.body)
+    }
+
+    /// The spans of the headword and the body as spans of `line`.
+    pub fn line_spans(&self) -> Vec<(usize, usize)> {
+        let body = self.headword.len() + 1;
+        self.headword_spans
+            .iter()
+            .copied()
+            .chain(self.body_spans.iter().map(|&(s, e)| (s + body, e + body)))
+            .collect()
+    }
+}
+
+/// The parts of the spans between `start` and `end`, relative to `start`.
+fn split_spans(spans: &[(usize, usize)], start: usize, end: usize) -> Vec<(usize, usize)> {
+    spans
+        .iter()
+        .map(|&(s, e)| (s.clamp(start, end), e.clamp(start, end)))
+        .filter(|(s, e)| s < e)
+        .map(|(s, e)| (s - start, e - start))
+        .collect()
+}
+
+/// Ranked hits from `offset` on, out of `total` hits in all.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    pub total: usize,
+    pub offset: usize,
+    pub hits: Vec<SearchHit>,
+}
+
+impl Page {
+    /// Cuts the page out of all the ranked hits.
+    pub fn new(hits: Vec<SearchHit>, offset: usize, limit: Option<usize>) -> Page {
+        let total = hits.len();
+        let hits = hits
+            .into_iter()
+            .skip(offset)
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+        Page {
+            total,
+            offset,
+            hits,
+        }
+    }
+
+    /// Whether some hits are left out of the page.
+    pub fn is_partial(&self) -> bool {
+        self.hits.len() < self.total
+    }
+
+    /// Such as `showing 1-100 of 2345`.
+    pub fn summary(&self) -> String {
+        match self.hits.len() {
+            0 => format!("showing 0 of {}", self.total),
+            n => format!(
+                "showing {}-{} of {}",
+                self.offset + 1,
+                self.offset + n,
+                self.total
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SearchHit;
+    use crate::normalize::Folding;
+    use crate::query::Query;
+
+    #[test]
+    fn splits_the_spans_at_the_tab() {
+        let hit = SearchHit::new(
+            "test",
+            0,
+            "cat\ta cat",
+            &Query::literal("cat"),
+            Folding::None,
+        );
+        assert_eq!(hit.headword_spans, vec![(0, 3)]);
+        assert_eq!(hit.body_spans, vec![(2, 5)]);
+        assert_eq!(hit.line_spans(), vec![(0, 3), (6, 9)]);
+        let hit = SearchHit::new("test", 0, "cat", &Query::literal("cat"), Folding::None);
+        assert_eq!((hit.headword.as_str(), hit.body.as_str()), ("cat", ""));
+        assert_eq!(hit.headword_spans, vec![(0, 3)]);
+        assert!(hit.body_spans.is_empty());
+    }
+}
@@ -1,141 +1,152 @@
-use encoding_rs;
+use encoding_rs::Encoding;
+use rayon::prelude::*;
+use std::borrow::Cow;
 use std::fs;
-use std::io::BufWriter;
-use std::io::Write;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+use crate::dictionary::{Dictionary, Format};
 use crate::eijiro_text_appender;
+use crate::external_sort::{self, ExternalSorter, Segment};
+use crate::header::{self, Fingerprint, Kind, Offsets};
+use crate::normalize::{normalize, Folding};
 
-pub fn _setup_tanaka_examples() {
-    const TANAKA: &str = "eiji-dict/tanaka-examples.utf";
-    println!("building the index of {}", TANAKA);
-    let utf8 = fs::read_to_string(TANAKA).unwrap();
-    let utf8 = utf8
-        .lines()
-        .filter(|s| s.starts_with("A: "))
-        .map(|s| &s[3..])
-        .map(|s| s.rsplitn(2, '#').collect::<Vec<&str>>()[1])
-        .collect::<Vec<&str>>()
-        .join("\n");
-    let mut words: Vec<[u8; 20]> = Vec::with_capacity(utf8.len());
-    let mut acc = 0u32;
-    for line in utf8.lines().map(|v| v.to_string()) {
-        add_segments(&mut words, &line, acc);
-        acc += line.len() as u32;
-        acc += "\n".len() as u32;
+/// Builds the index files of a dictionary from its sources.
+///
+/// ```no_run
+/// use eiji_sub_ngram::{Folding, Format, IndexBuilder, SearchOptions};
+///
+/// let dict = IndexBuilder::new(Format::Eijiro, &["EIJIRO-1448.TXT"])
+///     .encoding("shift_jis")
+///     .folding(Folding::Kana)
+///     .build("EIJIRO-1448")?;
+/// let hits = dict.search("同型", &SearchOptions::default());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct IndexBuilder {
+    format: Format,
+    sources: Vec<String>,
+    encoding: String,
+    folding: Folding,
+}
+
+impl IndexBuilder {
+    /// Sources are UTF-8 and there is no normalized index unless told
+    /// otherwise.
+    pub fn new(format: Format, sources: &[impl AsRef<Path>]) -> IndexBuilder {
+        IndexBuilder {
+            format,
+            sources: sources
+                .iter()
+                .map(|s| s.as_ref().display().to_string())
+                .collect(),
+            encoding: "utf-8".to_string(),
+            folding: Folding::None,
+        }
     }
-    words.sort();
-    words.dedup();
-    write_indices(words, super::TANAKA_NGRAM, super::TANAKA_INDEX);
-    fs::write(
-        super::TANAKA_TEXT,
-        &utf8.lines().collect::<Vec<&str>>().join("\n"),
-    )
-    .unwrap();
-    println!("indexing finished successfully.");
-}
-
-pub fn _setup_ted() {
-    const EN: &str = "eiji-dict/ted_train_en-ja.raw.en";
-    const JA: &str = "eiji-dict/ted_train_en-ja.raw.ja";
-    println!("building the index of {} and {}", EN, JA);
-    let en = fs::read_to_string(EN).unwrap();
-    let ja = fs::read_to_string(JA).unwrap();
-    let utf8 = en
-        .lines()
-        .zip(ja.lines())
-        .map(|(x, y)| [x, y].join("\t"))
-        .collect::<Vec<String>>()
-        .join("\n");
-    let mut words: Vec<[u8; 20]> = Vec::with_capacity(utf8.len());
-    let mut acc = 0u32;
-    for line in utf8.lines().map(|v| v.to_string()) {
-        add_segments(&mut words, &line, acc);
-        acc += line.len() as u32;
-        acc += "\n".len() as u32;
+
+    /// Encoding label of the sources, such as `shift_jis`.
+    pub fn encoding(mut self, label: &str) -> IndexBuilder {
+        self.encoding = label.to_string();
+        self
     }
-    words.sort();
-    words.dedup();
-    write_indices(words, super::TED_NGRAM, super::TED_INDEX);
-    fs::write(
-        super::TED_TEXT,
-        &utf8.lines().collect::<Vec<&str>>().join("\n"),
-    )
-    .unwrap();
-    println!("indexing finished successfully.");
-}
-
-pub fn _setup_subtitle() {
-    const SUBTITLE: &str = "eiji-dict/train";
-    println!("building the index of {}", SUBTITLE);
-    let utf8 = fs::read_to_string(SUBTITLE).unwrap();
-    let mut words: Vec<[u8; 20]> = Vec::with_capacity(utf8.len());
-    let mut acc = 0u32;
-    for line in utf8.lines().map(|v| v.to_string()) {
-        add_segments(&mut words, &line, acc);
-        acc += line.len() as u32;
-        acc += "\n".len() as u32;
+
+    /// Also builds a normalized index folded this way.
+    pub fn folding(mut self, folding: Folding) -> IndexBuilder {
+        self.folding = folding;
+        self
     }
-    words.sort();
-    words.dedup();
-    write_indices(words, super::SUBTITLE_NGRAM, super::SUBTITLE_INDEX);
-    fs::write(
-        super::SUBTITLE_TEXT,
-        &utf8.lines().collect::<Vec<&str>>().join("\n"),
-    )
-    .unwrap();
-    println!("indexing finished successfully.");
-}
-
-pub fn _setup_edict() {
-    const EDICT: &str = "eiji-dict/edict.tab";
-    println!("building the index of {}", EDICT);
-    let utf8 = fs::read_to_string(EDICT).unwrap();
-    let mut words: Vec<[u8; 20]> = Vec::with_capacity(utf8.len());
-    let mut acc = 0u32;
-    for line in utf8.lines().map(|v| v.to_string()) {
-        add_segments(&mut words, &line, acc);
-        acc += line.len() as u32;
-        acc += "\n".len() as u32;
+
+    /// Writes `{files}_NGRAM`, `{files}_INDEX`, `{files}_HEADWORD` and
+    /// `{files}_TEXT`, plus the normalized index, and opens the dictionary.
+    /// Fails when there are not as many sources as the format takes, a
+    /// source cannot be read or a file cannot be written.
+    pub fn build(self, files: impl AsRef<Path>) -> io::Result<Dictionary> {
+        let dict = Dictionary::at(files.as_ref(), self.sources, self.format, &self.encoding);
+        setup(&dict, self.folding)?;
+        Ok(dict)
+    }
+}
+
+/// Builds the index of a dictionary from its sources, with the normalized
+/// index too unless `folding` is `Folding::None`. Progress and timings are
+/// logged.
+pub fn setup(dict: &Dictionary, folding: Folding) -> io::Result<()> {
+    if dict.sources.len() != dict.format.sources() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} has {} sources but its format takes {}",
+                dict.name,
+                dict.sources.len(),
+                dict.format.sources()
+            ),
+        ));
     }
-    words.sort();
-    words.dedup();
-    write_indices(words, super::EDICT_NGRAM, super::EDICT_INDEX);
-    fs::write(
-        super::EDICT_TEXT,
-        &utf8.lines().collect::<Vec<&str>>().join("\n"),
-    )
-    .unwrap();
-    println!("indexing finished successfully.");
-}
-
-pub fn setup_eijiro() {
-    println!("building the index of {}", super::EIJIRO);
-    let sjis = fs::read(super::EIJIRO).unwrap();
-    let (utf8, _, _) = encoding_rs::SHIFT_JIS.decode(&sjis);
+    info!("building the index of {}", dict.sources.join(" and "));
+    let sources = dict
+        .sources
+        .iter()
+        .map(|source| read(source, &dict.encoding))
+        .collect::<io::Result<Vec<String>>>()?;
+    let fingerprint = Fingerprint::of(&dict.sources);
+    let text = match dict.format {
+        Format::Tsv => tsv(&sources[0]),
+        Format::Parallel => parallel(&sources[0], &sources[1]),
+        Format::Tanaka => tanaka_examples(&sources[0]),
+        Format::Eijiro => eijiro(&sources[0]),
+        Format::Reijiro => reijiro(&sources[0]),
+    };
+    build(&text, dict, folding, &fingerprint)?;
+    info!("indexing finished successfully.");
+    Ok(())
+}
+
+fn read(source: &str, encoding: &str) -> io::Result<String> {
+    let encoding = Encoding::for_label(encoding.as_bytes()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown encoding {}", encoding),
+        )
+    })?;
+    let bytes =
+        fs::read(source).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", source, e)))?;
+    let (text, _, _) = encoding.decode(&bytes);
+    Ok(text.into_owned())
+}
+
+fn tsv(utf8: &str) -> String {
+    utf8.lines().collect::<Vec<&str>>().join("\n")
+}
+
+fn parallel(en: &str, ja: &str) -> String {
+    en.lines()
+        .zip(ja.lines())
+        .map(|(x, y)| [x, y].join("\t"))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn tanaka_examples(utf8: &str) -> String {
+    utf8.lines()
+        .filter(|s| s.starts_with("A: "))
+        .map(|s| &s[3..])
+        .map(|s| s.rsplitn(2, '#').collect::<Vec<&str>>()[1])
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+fn eijiro(utf8: &str) -> String {
     let mut buf = eijiro_text_appender::TextAppender::new(utf8.len());
     for line in utf8.lines() {
         let separator = line.find(" : ").unwrap();
         buf.append(&line[3..separator], &line[separator + 3..]);
     }
-
-    let mut words: Vec<[u8; 20]> = Vec::with_capacity(buf.text.len());
-    let mut acc = 0u32;
-    for line in buf.text[1..].lines().map(|v| v.to_string()) {
-        add_segments(&mut words, &line, acc);
-        acc += line.len() as u32;
-        acc += "\n".len() as u32;
-    }
-    words.sort();
-    words.dedup();
-    write_indices(words, super::EIJIRO_NGRAM, super::EIJIRO_INDEX);
-    fs::write(super::EIJIRO_TEXT, &buf.text[1..]).unwrap();
-    println!("indexing finished successfully.");
+    buf.text[1..].to_string()
 }
 
-pub fn setup_reijiro() {
-    println!("building the index of {}", super::REIJIRO);
-    let sjis = fs::read(super::REIJIRO).unwrap();
-    let (utf8, _, _) = encoding_rs::SHIFT_JIS.decode(&sjis);
+fn reijiro(utf8: &str) -> String {
     let mut text = String::with_capacity(utf8.len());
     for line in utf8.lines() {
         let separator = line.find(" : ").unwrap();
@@ -155,46 +166,237 @@ pub fn setup_reijiro() {
             }
         }
     }
+    text[1..].to_string()
+}
 
-    let mut words: Vec<[u8; 20]> = Vec::with_capacity(text.len());
-    let mut acc = 0u32;
-    for line in text[1..].lines().map(|v| v.to_string()) {
-        add_segments(&mut words, &line, acc);
-        acc += line.len() as u32;
-        acc += "\n".len() as u32;
+/// Segments every line of the text and writes the n-gram, index, headword and
+/// text files, plus the normalized n-gram and index files when normalization
+/// is turned on.
+fn build(
+    text: &str,
+    dict: &Dictionary,
+    folding: Folding,
+    fingerprint: &Fingerprint,
+) -> io::Result<()> {
+    let mut lines = Vec::new();
+    let mut acc = 0u64;
+    for line in text.lines() {
+        lines.push((acc, line));
+        acc += line.len() as u64;
+        acc += "\n".len() as u64;
     }
-    words.sort();
-    words.dedup();
-    write_indices(words, super::REIJIRO_NGRAM, super::REIJIRO_INDEX);
-    fs::write(super::REIJIRO_TEXT, &text[1..]).unwrap();
-    println!("indexing finished successfully.");
-}
-
-fn write_indices(data: Vec<[u8; 20]>, ngram: &str, index: &str) {
-    let mut ngramf = BufWriter::new(fs::File::create(ngram).unwrap());
-    let mut indexf = BufWriter::new(fs::File::create(index).unwrap());
-    for &value in &data {
-        ngramf.write(&value[0..12]).unwrap();
-        indexf.write(&value[12..20]).unwrap();
+    let offsets = Offsets::for_text(acc);
+    write_ngrams(
+        &lines,
+        &dict.ngram_file(),
+        &dict.index_file(),
+        Folding::None,
+        offsets,
+        fingerprint,
+    )?;
+    if folding != Folding::None {
+        info!("  normalizing the text ({:?})", folding);
+        write_ngrams(
+            &lines,
+            &dict.norm_ngram_file(),
+            &dict.norm_index_file(),
+            folding,
+            offsets,
+            fingerprint,
+        )?;
     }
+
+    let started = Instant::now();
+    write_headwords(text, &dict.headword_file(), offsets, fingerprint)?;
+    info!("  headword index {:.2?}", started.elapsed());
+
+    let started = Instant::now();
+    write_text(text, &dict.text_file(), offsets, fingerprint)?;
+    info!("  text {:.2?}", started.elapsed());
+    Ok(())
 }
 
-fn add_segments(words: &mut Vec<[u8; 20]>, line: &String, offset: u32) {
-    let bs = line.as_bytes();
-    for p in 0..bs.len() {
-        let mut u = [0; 20];
-        for i in 0..12 {
-            u[i] = bs.get(p + i).map(|&v| v).unwrap_or(0);
+/// Segments the lines, normalized with `folding`, and writes the n-gram and
+/// index files.
+fn write_ngrams(
+    lines: &[(u64, &str)],
+    ngram: &str,
+    index: &str,
+    folding: Folding,
+    offsets: Offsets,
+    fingerprint: &Fingerprint,
+) -> io::Result<()> {
+    let started = Instant::now();
+    let budget = external_sort::memory_budget();
+    let total = lines.iter().map(|(_, line)| line.len()).sum();
+    let mut words = ExternalSorter::new(ngram, budget, total);
+    let capacity = words.capacity();
+    let mut window = 0;
+    while window < lines.len() {
+        // normalize about as many bytes at once as there is room for segments
+        let (end, _) = batch(|i| lines[i].1.len(), window, lines.len(), capacity);
+        let normalized = lines[window..end]
+            .par_iter()
+            .map(|&(offset, line)| {
+                let grams = match folding {
+                    Folding::None => Cow::Borrowed(line),
+                    _ => Cow::Owned(normalize(line, folding)),
+                };
+                (offset, line.len(), grams)
+            })
+            .collect::<Vec<_>>();
+        // a line yields one segment per character, which folding may add
+        let counts = normalized
+            .par_iter()
+            .map(|(_, _, grams)| grams.chars().count())
+            .collect::<Vec<usize>>();
+        let mut start = 0;
+        while start < normalized.len() {
+            let (end, segments) = batch(|i| counts[i], start, counts.len(), capacity);
+            let segs = normalized[start..end]
+                .par_iter()
+                .flat_map_iter(|(offset, len, grams)| segments_of(grams, *offset, *len));
+            words.extend(segs, segments)?;
+            start = end;
         }
-        u[12] = (offset >> 24) as u8;
-        u[13] = (offset >> 16) as u8;
-        u[14] = (offset >> 8) as u8;
-        u[15] = (offset >> 0) as u8;
-        let len = line.len();
-        u[16] = (len >> 24) as u8;
-        u[17] = (len >> 16) as u8;
-        u[18] = (len >> 8) as u8;
-        u[19] = (len >> 0) as u8;
-        words.push(u);
+        window = end;
+    }
+    let spilled = words.sorting();
+    info!("  segmentation {:.2?}", started.elapsed() - spilled);
+
+    let started = Instant::now();
+    let sorting = write_indices(words, ngram, index, folding, offsets, fingerprint)?;
+    info!("  sorting {:.2?}", sorting);
+    info!(
+        "  n-gram index {:.2?}",
+        started.elapsed() - (sorting - spilled)
+    );
+    Ok(())
+}
+
+/// Writes the sorted segments and tells how long sorting them took.
+fn write_indices(
+    data: ExternalSorter,
+    ngram: &str,
+    index: &str,
+    folding: Folding,
+    offsets: Offsets,
+    fingerprint: &Fingerprint,
+) -> io::Result<Duration> {
+    let (ngram_kind, index_kind) = match folding {
+        Folding::None => (Kind::Ngram, Kind::Index),
+        _ => (Kind::NormalizedNgram, Kind::NormalizedIndex),
+    };
+    let mut ngramf = create(ngram)?;
+    let mut indexf = create(index)?;
+    ngramf.write_all(&header::encode(ngram_kind, offsets, folding, fingerprint))?;
+    indexf.write_all(&header::encode(index_kind, offsets, folding, fingerprint))?;
+    let sorting = data.finish(|(gram, offset, len)| {
+        ngramf.write_all(gram)?;
+        write_record(&mut indexf, offsets, *offset, *len)
+    })?;
+    ngramf.flush()?;
+    indexf.flush()?;
+    Ok(sorting)
+}
+
+/// Sorts the lines by their headword, the text before the tab, and writes
+/// their (offset, length) records in that order for prefix search.
+fn write_headwords(
+    text: &str,
+    headword: &str,
+    offsets: Offsets,
+    fingerprint: &Fingerprint,
+) -> io::Result<()> {
+    let mut entries = Vec::new();
+    let mut acc = 0u64;
+    for line in text.lines() {
+        let title = line.split('\t').next().unwrap_or(line);
+        entries.push((title, acc, line.len() as u32));
+        acc += line.len() as u64;
+        acc += "\n".len() as u64;
+    }
+    entries.par_sort_unstable();
+    let mut headwordf = create(headword)?;
+    headwordf.write_all(&header::encode(
+        Kind::Headword,
+        offsets,
+        Folding::None,
+        fingerprint,
+    ))?;
+    for (_, offset, len) in entries {
+        write_record(&mut headwordf, offsets, offset, len)?;
     }
+    headwordf.flush()
+}
+
+/// Writes a line offset of the given width followed by the line length.
+pub fn write_record(w: &mut impl Write, offsets: Offsets, offset: u64, len: u32) -> io::Result<()> {
+    match offsets {
+        Offsets::Narrow => w.write_all(&(offset as u32).to_be_bytes())?,
+        Offsets::Wide => w.write_all(&offset.to_be_bytes())?,
+    }
+    w.write_all(&len.to_be_bytes())
+}
+
+fn write_text(
+    text: &str,
+    path: &str,
+    offsets: Offsets,
+    fingerprint: &Fingerprint,
+) -> io::Result<()> {
+    let mut textf = create(path)?;
+    textf.write_all(&header::encode(
+        Kind::Text,
+        offsets,
+        Folding::None,
+        fingerprint,
+    ))?;
+    textf.write_all(text.as_bytes())?;
+    textf.flush()
+}
+
+/// Creates a buffered file, naming it in the error when it cannot be.
+fn create(path: &str) -> io::Result<BufWriter<fs::File>> {
+    Ok(BufWriter::new(
+        fs::File::create(path).map_err(|e| naming(path, e))?,
+    ))
+}
+
+/// Adds the file to the message of an error.
+fn naming(path: &str, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path, e))
+}
+
+/// The end of the batch of items from `start` on whose sizes add up to at
+/// most `capacity`, with at least one item, and their total size.
+fn batch(
+    size: impl Fn(usize) -> usize,
+    start: usize,
+    len: usize,
+    capacity: usize,
+) -> (usize, usize) {
+    let (mut end, mut total) = (start + 1, size(start));
+    while end < len && total + size(end) <= capacity {
+        total += size(end);
+        end += 1;
+    }
+    (end, total)
+}
+
+/// Segments of `grams` starting at every character, all pointing to the same
+/// line. Segments never start in the middle of a multibyte character since a
+/// keyword cannot match there.
+fn segments_of(grams: &str, offset: u64, len: usize) -> Vec<Segment> {
+    let bs = grams.as_bytes();
+    grams
+        .char_indices()
+        .map(|(p, _)| {
+            let mut gram = [0; 12];
+            for (i, g) in gram.iter_mut().enumerate() {
+                *g = bs.get(p + i).copied().unwrap_or(0);
+            }
+            (gram, offset, len as u32)
+        })
+        .collect()
 }
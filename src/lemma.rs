@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::dictionary::{Dictionary, SearchOptions};
+use crate::hit::{Page, SearchHit};
+use crate::query::{Field, Query};
+
+/// The exception lists of WordNet, irregular forms followed by their base
+/// forms, such as `ran run` in `verb.exc`.
+const EXCEPTION_FILES: [&str; 4] = ["noun.exc", "verb.exc", "adj.exc", "adv.exc"];
+
+/// Common irregular forms in the format of the exception lists, so that
+/// `ran` or `mice` are found without WordNet installed.
+const IRREGULAR: &str = include_str!("irregular.exc");
+
+/// The detachment rules of morphy: an inflected ending and what replaces
+/// it, by part of speech.
+const RULES: [(&str, &str); 18] = [
+    // nouns
+    ("s", ""),
+    ("ses", "s"),
+    ("xes", "x"),
+    ("zes", "z"),
+    ("ches", "ch"),
+    ("shes", "sh"),
+    ("men", "man"),
+    ("ies", "y"),
+    // verbs
+    ("es", "e"),
+    ("es", ""),
+    ("ed", "e"),
+    ("ed", ""),
+    ("ing", "e"),
+    ("ing", ""),
+    // adjectives
+    ("er", ""),
+    ("est", ""),
+    ("er", "e"),
+    ("est", "e"),
+];
+
+/// Finds the base forms of English words the way WordNet's morphy does,
+/// from the exception lists when they are there, then from the rules.
+#[derive(Debug, Default)]
+pub struct Lemmatizer {
+    exceptions: HashMap<String, Vec<String>>,
+}
+
+impl Lemmatizer {
+    /// Knows the common irregular forms built into the binary.
+    pub fn builtin() -> Lemmatizer {
+        let mut lemmatizer = Lemmatizer::default();
+        lemmatizer.add_exceptions(IRREGULAR);
+        lemmatizer
+    }
+
+    /// Reads the `*.exc` files of a WordNet `dict` directory, those present,
+    /// on top of the built-in irregular forms.
+    pub fn load(dir: &Path) -> Lemmatizer {
+        let mut lemmatizer = Lemmatizer::builtin();
+        for file in EXCEPTION_FILES {
+            match fs::read_to_string(dir.join(file)) {
+                Ok(list) => lemmatizer.add_exceptions(&list),
+                Err(e) => info!("{}: {}", dir.join(file).display(), e),
+            }
+        }
+        lemmatizer
+    }
+
+    /// Adds the lines of an exception list, `form base...`. WordNet joins
+    /// the words of a collocation with `_`.
+    pub fn add_exceptions(&mut self, list: &str) {
+        for line in list.lines() {
+            let mut words = line.split_whitespace().map(|w| w.replace('_', " "));
+            if let Some(form) = words.next() {
+                let bases = self.exceptions.entry(form).or_default();
+                for base in words {
+                    if !bases.contains(&base) {
+                        bases.push(base);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Candidate base forms of a word other than the word itself, those of
+    /// the exception lists first. The rules also give forms which are not
+    /// words, such as `studi` for `studies`.
+    pub fn lemmas(&self, word: &str) -> Vec<String> {
+        let word = word.to_lowercase();
+        let mut lemmas = self.exceptions.get(&word).cloned().unwrap_or_default();
+        for (ending, replacement) in RULES {
+            if let Some(stem) = word.strip_suffix(ending) {
+                // `is` or `as` are not plurals of a letter
+                let shortest = if replacement.is_empty() && ending.ends_with('s') {
+                    2
+                } else {
+                    1
+                };
+                if stem.chars().count() >= shortest {
+                    lemmas.push(format!("{}{}", stem, replacement));
+                }
+            }
+        }
+        let mut unique = vec![];
+        for lemma in lemmas {
+            if lemma != word && !unique.contains(&lemma) {
+                unique.push(lemma);
+            }
+        }
+        unique
+    }
+
+    /// The hits of the query in the dictionaries, sorted, followed by the
+    /// entries whose headword is one of its base forms, sorted and labeled
+    /// with the base form they were found via. Only a single plain word is
+    /// lemmatized.
+    pub fn search(
+        &self,
+        dicts: &[&Dictionary],
+        query: &str,
+        options: &SearchOptions,
+    ) -> Vec<SearchHit> {
+        self.search_page(dicts, query, options).hits
+    }
+
+    /// Like `search`, also telling how many hits there are in all. The page
+    /// is cut once all the hits are merged.
+    pub fn search_page(&self, dicts: &[&Dictionary], query: &str, options: &SearchOptions) -> Page {
+        let every = SearchOptions {
+            offset: 0,
+            limit: None,
+            ..*options
+        };
+        let gather = |search: &dyn Fn(&Dictionary) -> Vec<SearchHit>| {
+            let mut hits = dicts
+                .iter()
+                .flat_map(|dict| search(dict))
+                .collect::<Vec<_>>();
+            options.sort(&mut hits);
+            hits
+        };
+        let mut hits = gather(&|dict| dict.search(query, &every));
+        if !self.applies(query, options) {
+            return Page::new(hits, options.offset, options.limit);
+        }
+        for lemma in self.lemmas(query) {
+            for mut hit in gather(&|dict| dict.headword_search(&lemma, &every)) {
+                let found =
+                    |h: &SearchHit| h.dictionary == hit.dictionary && h.offset == hit.offset;
+                if !hits.iter().any(found) {
+                    hit.lemma = Some(lemma.clone());
+                    hits.push(hit);
+                }
+            }
+        }
+        Page::new(hits, options.offset, options.limit)
+    }
+
+    /// Whether the query is a word of letters searched as it is.
+    fn applies(&self, query: &str, options: &SearchOptions) -> bool {
+        if options.prefix || options.regex || options.wildcard {
+            return false;
+        }
+        let parsed = Query::parse(query);
+        matches!(&parsed.alternatives[..], [terms] if terms.len() == 1
+            && !terms[0].negated
+            && terms[0].field == Field::Any
+            && terms[0].text.chars().all(char::is_alphabetic)
+            && terms[0].text.is_ascii())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lemmatizer;
+
+    #[test]
+    fn finds_base_forms_by_exception_then_by_rule() {
+        let mut lemmatizer = Lemmatizer::default();
+        lemmatizer.add_exceptions(
+            "ran run\nmice mouse\nbetter good well\nbrothers-in-law brother-in-law\n",
+        );
+        assert_eq!(lemmatizer.lemmas("ran"), ["run"]);
+        assert_eq!(lemmatizer.lemmas("Mice"), ["mouse"]);
+        assert_eq!(
+            lemmatizer.lemmas("better"),
+            ["good", "well", "bett", "bette"]
+        );
+        assert!(lemmatizer.lemmas("studies").contains(&"study".to_string()));
+        assert!(lemmatizer.lemmas("boxes").contains(&"box".to_string()));
+        assert!(lemmatizer.lemmas("running").contains(&"runn".to_string()));
+        assert!(lemmatizer.lemmas("is").is_empty());
+        assert_eq!(lemmatizer.lemmas("uses"), ["use", "us"]);
+        assert!(lemmatizer.lemmas("s").is_empty());
+        assert!(lemmatizer.lemmas("run").is_empty());
+    }
+
+    #[test]
+    fn knows_common_irregular_forms() {
+        let lemmatizer = Lemmatizer::builtin();
+        assert_eq!(lemmatizer.lemmas("ran")[0], "run");
+        assert_eq!(lemmatizer.lemmas("Mice")[0], "mouse");
+        assert_eq!(lemmatizer.lemmas("children")[0], "child");
+        assert_eq!(lemmatizer.lemmas("running")[0], "run");
+        assert_eq!(lemmatizer.lemmas("better")[..2], ["good", "well"]);
+        assert!(Lemmatizer::default().lemmas("ran").is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+//! Full text search of English-Japanese dictionaries through n-gram indices.
+//!
+//! [`IndexBuilder`] turns the sources of a dictionary into index files and
+//! [`Dictionary::open`] opens them for [`Dictionary::search`]. [`Registry`]
+//! lists the dictionaries of `dictionaries.toml`.
+
+#[macro_use]
+extern crate log;
+
+mod data_dir;
+mod dictionary;
+mod eijiro_text_appender;
+mod external_sort;
+pub mod header;
+mod hit;
+mod indexing;
+mod lemma;
+pub mod normalize;
+mod query;
+mod rank;
+#[cfg(test)]
+mod scratch;
+mod search;
+mod suggest;
+
+pub use data_dir::DataDirs;
+pub use dictionary::{Dictionary, Format, Registry, SearchOptions};
+pub use hit::{Page, SearchHit};
+pub use indexing::IndexBuilder;
+pub use lemma::Lemmatizer;
+pub use normalize::Folding;
+pub use query::{Field, Query, Term};
+pub use rank::{by_length, rank, Relevance};
+pub use suggest::Suggestion;
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        dictionary::{Dictionary, Format, Registry, SearchOptions},
+        hit::SearchHit,
+        indexing::{setup, IndexBuilder},
+        lemma::Lemmatizer,
+        normalize::Folding,
+        scratch::Scratch,
+        search::{load_then_filter, ngram_search, prefix_search},
+    };
+    use std::str::FromStr;
+    use std::sync::OnceLock;
+
+    /// The EDICT dictionary of the default registry, built once for all the
+    /// tests.
+    fn edict() -> &'static Dictionary {
+        static EDICT: OnceLock<Dictionary> = OnceLock::new();
+        EDICT.get_or_init(|| {
+            let edict = Registry::default().get("edict").unwrap().clone();
+            setup(&edict, Folding::None).unwrap();
+            edict
+        })
+    }
+
+    /// Headwords of the hits of a query, sorted.
+    fn headwords(query: &str, options: &SearchOptions) -> Vec<String> {
+        let hits = edict().search(query, options);
+        let mut found = hits.into_iter().map(|h| h.headword).collect::<Vec<_>>();
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn journey_tests() {
+        let edict = edict();
+        let (ngram, index) = (&edict.ngram_file(), &edict.index_file());
+        let text = &edict.text_file();
+        {
+            let keyword = String::from_str("同型").unwrap();
+            let occurences = ngram_search(&keyword, ngram, index);
+            assert_eq!(
+                occurences,
+                vec![(4455295, 102), (7362715, 82), (7364068, 82), (7364580, 78)]
+            );
+            let findings = load_then_filter(&keyword, &occurences, text);
+            assert_eq!(
+                findings,
+                vec![
+                    "isomorphism\t/aɪsoʊmɔːfɪzəm/aisoùm<ħ>ò</ħ>fizøm/ 同型 [どうけい],同形 [どうけい]",
+                    "same pattern\t/sɛɪm pætn/seim pätn/ 同型 [どうけい],同形 [どうけい]",
+                    "same shape\t/sɛɪm ʃɛɪp/seim ŝeip/ 同型 [どうけい],同形 [どうけい]",
+                    "same type\t/sɛɪm taɪp/seim taip/ 同型 [どうけい],同形 [どうけい]"
+                ]
+            );
+        }
+        {
+            let keyword = String::from_str("🍁").unwrap();
+            let occurences = ngram_search(&keyword, ngram, index);
+            assert_eq!(occurences, vec![]);
+            let findings = load_then_filter(&keyword, &occurences, text);
+            assert_eq!(findings, Vec::<String>::new());
+        }
+        {
+            let keyword = String::from_str("!").unwrap();
+            let occurences = ngram_search(&keyword, ngram, index);
+            assert_eq!(occurences.len(), 96);
+            let findings = load_then_filter(&keyword, &occurences, text);
+            assert_eq!(findings[0], "a happy new year!\t/ə hæpiː njuː -/ø häp<ħ>ï</ħ> nj<ħ>u</ħ> -/ 賀正 [がしょう],賀正 [がせい]");
+            assert_eq!(findings[95], "yuck!\t/-/-/ 最低 [さいてい]");
+        }
+        {
+            let keyword = String::from_str("\t\t\t!").unwrap();
+            let occurences = ngram_search(&keyword, ngram, index);
+            assert_eq!(occurences, vec![]);
+            let findings = load_then_filter(&keyword, &occurences, text);
+            assert_eq!(findings, Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn looks_up_keywords_longer_than_an_ngram() {
+        let edict = edict();
+        let (ngram, index, text) = (&edict.ngram_file(), &edict.index_file(), &edict.text_file());
+        // 12 bytes would end in the middle of "ど"
+        let keyword = String::from_str("同型 [どうけい]").unwrap();
+        let occurences = ngram_search(&keyword, ngram, index);
+        assert_eq!(
+            occurences,
+            vec![(4455295, 102), (7362715, 82), (7364068, 82), (7364580, 78)]
+        );
+        let findings = load_then_filter(&keyword, &occurences, text);
+        assert_eq!(findings.len(), 4);
+    }
+
+    #[test]
+    fn searches_headwords_by_prefix() {
+        let edict = edict();
+        let (headword, text) = (&edict.headword_file(), &edict.text_file());
+        let keyword = String::from_str("isomorph").unwrap();
+        let occurences = prefix_search(&keyword, headword, text);
+        let findings = load_then_filter(&keyword, &occurences, text);
+        assert!(findings.iter().all(|l| l.starts_with("isomorph")));
+        assert!(findings.contains(&String::from(
+            "isomorphism\t/aɪsoʊmɔːfɪzəm/aisoùm<ħ>ò</ħ>fizøm/ 同型 [どうけい],同形 [どうけい]"
+        )));
+    }
+
+    #[test]
+    fn opens_the_index_files_of_a_dictionary() {
+        let ngram = edict().ngram_file();
+        let opened = Dictionary::open(ngram.strip_suffix("_NGRAM").unwrap()).unwrap();
+        let hits = opened.search("同型", &SearchOptions::default());
+        let hit = hits.iter().find(|h| h.headword == "isomorphism").unwrap();
+        assert_eq!(hit.offset, 4455295);
+        assert_eq!(hit.body_spans, vec![(47, 53)]);
+        assert_eq!(&hit.body[47..53], "同型");
+        assert!(Dictionary::open("NO_SUCH_DICTIONARY").is_err());
+    }
+
+    #[test]
+    fn finds_nothing_when_the_files_are_gone() {
+        let (scratch, dict) = built("gone", "cat\t猫\n");
+        assert_eq!(dict.search("cat", &SearchOptions::default()).len(), 1);
+        std::fs::remove_file(scratch.file("GONE_TEXT")).unwrap();
+        assert!(dict.search("cat", &SearchOptions::default()).is_empty());
+        let prefix = SearchOptions {
+            prefix: true,
+            ..Default::default()
+        };
+        assert!(dict.search("ca", &prefix).is_empty());
+        std::fs::remove_file(scratch.file("GONE_NGRAM")).unwrap();
+        assert!(dict.search("猫", &SearchOptions::default()).is_empty());
+        assert!(!dict.has_headword("cat"));
+    }
+
+    #[test]
+    fn ranks_then_pages_the_hits() {
+        let options = SearchOptions {
+            limit: Some(3),
+            ..Default::default()
+        };
+        // all four translate as 同型, the shortest come first
+        let hits = edict().search("同型", &options);
+        let headwords = hits.iter().map(|h| h.headword.as_str()).collect::<Vec<_>>();
+        assert_eq!(headwords, vec!["same type", "same pattern", "same shape"]);
+        let lines = hits.iter().map(SearchHit::line).collect::<Vec<_>>();
+        assert_eq!(edict().find("同型", &options), lines);
+        let options = SearchOptions {
+            offset: 3,
+            ..options
+        };
+        let page = edict().search_page("同型", &options);
+        assert_eq!(page.total, 4);
+        assert_eq!(page.hits.len(), 1);
+        assert_eq!(page.hits[0].headword, "isomorphism");
+        assert_eq!(page.summary(), "showing 4-4 of 4");
+    }
+
+    #[test]
+    fn combines_terms_with_and_or_not() {
+        let options = SearchOptions::default();
+        assert_eq!(
+            headwords("同型 same", &options),
+            ["same pattern", "same shape", "same type"]
+        );
+        assert_eq!(headwords("同型 -same", &options), ["isomorphism"]);
+        assert_eq!(
+            headwords("\"same type\" | isomorphism", &options),
+            ["isomorphism", "same type"]
+        );
+        assert_eq!(headwords("同型 ée", &options), Vec::<String>::new());
+        let hits = edict().search("same 同型", &options);
+        assert_eq!(hits[0].headword_spans, vec![(0, 4)]);
+        assert_eq!(hits[0].body_spans.len(), 1);
+    }
+
+    #[test]
+    fn scopes_terms_to_the_headword_or_the_body() {
+        let options = SearchOptions::default();
+        assert_eq!(headwords("同型 hw:iso", &options), ["isomorphism"]);
+        assert_eq!(headwords("hw:同型", &options), Vec::<String>::new());
+        assert_eq!(headwords("body:同型 -hw:same", &options), ["isomorphism"]);
+    }
+
+    #[test]
+    fn searches_by_regular_expression() {
+        let regex = SearchOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let found = edict().find("^same (type|shape)\t", &regex);
+        assert_eq!(found.len(), 2);
+        // no literal to look up, every line is scanned
+        let hits = edict().search("(?i)^ISOMORPHISM\\s", &regex);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].headword_spans, vec![(0, 11)]);
+        assert!(edict().search("(", &regex).is_empty());
+    }
+
+    #[test]
+    fn matches_headwords_against_wildcard_patterns() {
+        let wildcard = SearchOptions {
+            wildcard: true,
+            ..Default::default()
+        };
+        let found = edict().find("same t?pe", &wildcard);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("same type\t"));
+        let hits = edict().search("*omorphism", &wildcard);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].headword_spans, vec![(0, 11)]);
+        let hits = edict().search("isomorph*", &wildcard);
+        assert_eq!(hits[0].headword, "isomorphic");
+        assert!(hits
+            .windows(2)
+            .all(|w| w[0].headword.chars().count() <= w[1].headword.chars().count()));
+    }
+
+    #[test]
+    fn suggests_close_headwords() {
+        assert!(edict()
+            .search("isomorhpism", &SearchOptions::default())
+            .is_empty());
+        let suggestions = edict().suggest("Isomorhpism", 3);
+        assert_eq!(suggestions[0].headword, "isomorphism");
+        assert_eq!(suggestions[0].distance, 2);
+        assert!(edict().suggest("zzzzzzzz", 3).is_empty());
+    }
+
+    /// A dictionary of the given lines built in a fresh directory, which
+    /// goes away with the returned `Scratch`.
+    fn built(name: &str, lines: &str) -> (Scratch, Dictionary) {
+        let scratch = Scratch::new(name);
+        let source = scratch.file(&format!("{}.tab", name));
+        std::fs::write(&source, lines).unwrap();
+        let dict = IndexBuilder::new(Format::Tsv, &[&source])
+            .build(scratch.file(&name.to_uppercase()))
+            .unwrap();
+        (scratch, dict)
+    }
+
+    #[test]
+    fn refuses_a_parallel_corpus_of_one_file() {
+        let scratch = Scratch::new("half");
+        let source = scratch.file("en.txt");
+        std::fs::write(&source, "a cat\n").unwrap();
+        let e = IndexBuilder::new(Format::Parallel, &[&source])
+            .build(scratch.file("HALF"))
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn finds_irregular_forms_via_the_builtin_list() {
+        let lemmatizer = Registry::default().lemmatizer;
+        let (_verbs, verbs) = built("verbs", "run\t走る\nrun away\t逃げる\nmouse\tネズミ\n");
+        let (_more, more) = built("more", "run\t運営する\nrerun\t再実行する\n");
+        let dicts = [&verbs, &more];
+        let options = SearchOptions::default();
+        let all = lemmatizer.search(&dicts, "ran", &options);
+        assert_eq!(all.len(), 2);
+        assert!(all.iter().all(|h| h.headword == "run"));
+        assert!(all.iter().all(|h| h.lemma.as_deref() == Some("run")));
+        let hits = lemmatizer.search(&dicts, "mice", &options);
+        assert_eq!(hits[0].headword, "mouse");
+
+        // the page is cut across the dictionaries, after the lemma hits
+        let paged = SearchOptions {
+            offset: 1,
+            limit: Some(2),
+            ..options
+        };
+        let page = lemmatizer.search_page(&dicts, "ran", &paged);
+        assert_eq!(page.total, 2);
+        let at = |hits: &[SearchHit]| {
+            hits.iter()
+                .map(|h| (h.dictionary.clone(), h.offset))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(at(&page.hits), at(&all[1..]));
+    }
+
+    #[test]
+    fn finds_inflected_words_via_their_lemma() {
+        let lemmatizer = Lemmatizer::default();
+        let hits = lemmatizer.search(&[edict()], "isomorphisms", &SearchOptions::default());
+        assert_eq!(hits[0].headword, "isomorphism");
+        assert_eq!(hits[0].lemma.as_deref(), Some("isomorphism"));
+        assert!(edict().has_headword("isomorphism"));
+        assert!(!edict().has_headword("isomorph"));
+    }
+
+    #[test]
+    fn question_marks_are_searched_as_they_are() {
+        let (_dir, dict) = built(
+            "questions",
+            "what?\t何だって\nreply\tthe answer to what?\nhow are you?\tお元気ですか\ncat\t猫\n",
+        );
+        let headwords = |input| {
+            let (query, options) = SearchOptions::for_query(input, Folding::None);
+            let hits = dict.search(query, &options);
+            let mut found = hits.into_iter().map(|h| h.headword).collect::<Vec<_>>();
+            found.sort();
+            found
+        };
+        assert_eq!(headwords("what?"), ["reply", "what?"]);
+        assert_eq!(headwords("you?"), ["how are you?"]);
+        assert_eq!(headwords("~c?t"), ["cat"]);
+        assert_eq!(headwords("~wha??"), ["what?"]);
+    }
+}
@@ -1,200 +1,260 @@
-mod eijiro_text_appender;
-mod indexing;
+mod commands;
+mod pager;
 mod print;
-mod search;
 
 #[macro_use]
 extern crate log;
 
+use std::env;
 use std::io;
 use std::io::Write;
-use std::path::Path;
+use std::path::PathBuf;
+use std::process;
 
-const EDICT_NGRAM: &str = "EDICT_NGRAM";
-const EDICT_INDEX: &str = "EDICT_INDEX";
-const EDICT_TEXT: &str = "EDICT_TEXT";
+use clap::{Parser, Subcommand};
 
-const SUBTITLE_NGRAM: &str = "SUBTITLE_NGRAM";
-const SUBTITLE_INDEX: &str = "SUBTITLE_INDEX";
-const SUBTITLE_TEXT: &str = "SUBTITLE_TEXT";
+use commands::OutputFormat;
+use eiji_sub_ngram::{DataDirs, Dictionary, Lemmatizer, Page, Registry, SearchOptions};
+use print::ColorMode;
 
-const EIJIRO: &str = "EIJIRO-1448.TXT";
-const EIJIRO_TEXT: &str = "EIJIRO-1448_TEXT";
-const EIJIRO_NGRAM: &str = "EIJIRO-1448_NGRAM";
-const EIJIRO_INDEX: &str = "EIJIRO-1448_INDEX";
+/// Hits of every dictionary group shown at once by the interactive search.
+const CONSOLE_PAGE: usize = 500;
 
-const REIJIRO: &str = "REIJI-1441.TXT";
-const REIJIRO_TEXT: &str = "REIJI-1441_TEXT";
-const REIJIRO_NGRAM: &str = "REIJI-1441_NGRAM";
-const REIJIRO_INDEX: &str = "REIJI-1441_INDEX";
-
-const TANAKA_NGRAM: &str = "TANAKA_NGRAM";
-const TANAKA_INDEX: &str = "TANAKA_INDEX";
-const TANAKA_TEXT: &str = "TANAKA_TEXT";
-
-const TED_NGRAM: &str = "TED_NGRAM";
-const TED_INDEX: &str = "TED_INDEX";
-const TED_TEXT: &str = "TED_TEXT";
-
-fn check_reijiro() {
-    let source_path = Path::new(REIJIRO);
-    let ngram_path = Path::new(REIJIRO_NGRAM);
-    let index_path = Path::new(REIJIRO_INDEX);
-    let text_path = Path::new(REIJIRO_TEXT);
-    println!("{} exists:{}", REIJIRO, source_path.exists());
-    println!("{} exists:{}", REIJIRO_NGRAM, ngram_path.exists());
-    println!("{} exists:{}", REIJIRO_INDEX, index_path.exists());
-    println!("{} exists:{}", REIJIRO_TEXT, text_path.exists());
-    if source_path.exists() && !text_path.exists() {
-        indexing::setup_reijiro();
-    }
+#[derive(Parser)]
+#[command(version, about = "Full text search of English-Japanese dictionaries")]
+struct Cli {
+    /// Directory holding dictionaries.toml, the sources and the generated
+    /// files, instead of EIJI_DATA_DIR and the XDG data directories
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+    /// Whether to color the output
+    #[arg(long, global = true, value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn check_eijiro() {
-    let source_path = Path::new(EIJIRO);
-    let ngram_path = Path::new(EIJIRO_NGRAM);
-    let index_path = Path::new(EIJIRO_INDEX);
-    let text_path = Path::new(EIJIRO_TEXT);
-    println!("{} exists:{}", EIJIRO, source_path.exists());
-    println!("{} exists:{}", EIJIRO_NGRAM, ngram_path.exists());
-    println!("{} exists:{}", EIJIRO_INDEX, index_path.exists());
-    println!("{} exists:{}", EIJIRO_TEXT, text_path.exists());
-    if source_path.exists() && !text_path.exists() {
-        indexing::setup_eijiro();
-    }
+#[derive(Subcommand)]
+enum Command {
+    /// Search interactively through the pager, the default
+    Search {
+        /// Search the closest headword right away when nothing is found
+        #[arg(long)]
+        auto_correct: bool,
+    },
+    /// Print the hits of a word as plain lines, exit 1 when there are none
+    Lookup {
+        /// Terms joined by spaces, `|` and `-term`, `"a phrase"`, `hw:term`,
+        /// `body:term`, `prefix*`, `~c?t` or `/regex`
+        #[arg(required = true)]
+        word: Vec<String>,
+        /// Dictionaries to search, all enabled ones by default
+        #[arg(long, value_delimiter = ',')]
+        dict: Vec<String>,
+        /// Skip this many of the ranked hits
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Print at most this many hits, all by default
+        #[arg(long)]
+        limit: Option<usize>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+        format: OutputFormat,
+    },
+    /// Build the index of dictionaries from their sources
+    Build {
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        dict: Vec<String>,
+        /// Every enabled dictionary whose sources exist
+        #[arg(long)]
+        all: bool,
+    },
+    /// Check that the generated files are present and up to date
+    Verify,
+    /// Print the size and the number of records of the generated files
+    Stats,
+    /// Answer lookups over HTTP at /lookup?q=<word>&dict=a,b&limit=N&format=json
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8019")]
+        addr: String,
+    },
 }
 
 fn main() {
     env_logger::init();
-    // indexing::_setup_edict();
-    // indexing::_setup_subtitle();
-    // indexing::_setup_ted();
-    // indexing::_setup_tanaka_examples();
-    check_eijiro();
-    check_reijiro();
+    let cli = Cli::parse();
+    print::set_color(cli.color);
+    let dirs = DataDirs::resolve(cli.data_dir, |var| env::var_os(var));
+    let registry = match Registry::load(&dirs) {
+        Ok(registry) => registry,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    let command = cli.command.unwrap_or(Command::Search {
+        auto_correct: false,
+    });
+    let code = match command {
+        Command::Search { auto_correct } => search(&registry, &dirs, auto_correct),
+        Command::Lookup {
+            word,
+            dict,
+            offset,
+            limit,
+            format,
+        } => commands::lookup(
+            &word.join(" "),
+            &dict,
+            offset,
+            limit,
+            format,
+            &registry,
+            &dirs,
+        ),
+        Command::Build { dict, all } => commands::build(&dict, all, &registry, &dirs),
+        Command::Verify => commands::verify(&registry, &dirs),
+        Command::Stats => {
+            commands::stats(&registry);
+            0
+        }
+        Command::Serve { addr } => commands::serve(&addr, &registry, &dirs),
+    };
+    process::exit(code);
+}
+
+fn search(registry: &Registry, dirs: &DataDirs, auto_correct: bool) -> i32 {
+    let folding = registry.normalize;
+    let dicts = registry
+        .enabled()
+        .filter(|dict| commands::check(dict, folding, dirs, true))
+        .collect::<Vec<&Dictionary>>();
 
-    println!("\x1b[0m\x1b[1;32m検索文字\x1b[0m(Enter)で検索");
-    println!("\x1b[1;33md\x1b[0mで画面をスクロール \x1b[1;33mq\x1b[0mで次の辞書");
-    println!("\x1b[1;36mctrl+c\x1b[0mでソフトウェアを終了");
+    let colored = print::colored(&io::stdout());
+    let key = |style: &str, text: &str| print::paint(style, text, colored);
+    println!("{}(Enter)で検索", key("1;32", "検索文字"));
+    println!("{}で見出し語の前方一致検索", key("1;32", "検索文字*"));
+    println!("{}で正規表現検索", key("1;32", "/正規表現"));
+    println!(
+        "{}で見出し語のワイルドカード検索(長さ順)",
+        key("1;32", "~c?t ~*ness")
+    );
+    println!(
+        "{}でかつ {}でまたは {}で除外 {}で語句",
+        key("1;32", "a b"),
+        key("1;32", "a | b"),
+        key("1;32", "-a"),
+        key("1;32", "\"a b\"")
+    );
+    println!(
+        "{}で見出し語のみ {}で訳語のみ",
+        key("1;32", "hw:a"),
+        key("1;32", "body:a")
+    );
+    println!(
+        "{}で画面をスクロール {}で次の辞書",
+        key("1;33", "d"),
+        key("1;33", "q")
+    );
+    println!(
+        "{}で次の{}件 {}で前の{}件",
+        key("1;32", ">"),
+        CONSOLE_PAGE,
+        key("1;32", "<"),
+        CONSOLE_PAGE
+    );
+    println!("{}でソフトウェアを終了", key("1;36", "ctrl+c"));
 
+    let mut last: Option<String> = None;
+    let mut offset = 0;
     loop {
-        let input: String = get_input("");
+        let Some(input) = get_input("") else {
+            return 0;
+        };
         if input.trim().is_empty() {
             continue;
         }
-
-        edict_eiji(&input);
-        {
-            let nums = search::ngram_search(&input, SUBTITLE_NGRAM, SUBTITLE_INDEX);
-            let hits = search::load_then_filter(&input, &nums, SUBTITLE_TEXT);
-            print::print_to_console(&input, hits);
-        }
-        if Path::new(REIJIRO_TEXT).exists() {
-            let nums = search::ngram_search(&input, REIJIRO_NGRAM, REIJIRO_INDEX);
-            let hits = search::load_then_filter(&input, &nums, REIJIRO_TEXT);
-            print::print_to_console(&input, hits);
+        let input = match (input.as_str(), &last) {
+            (">", Some(last)) => {
+                offset += CONSOLE_PAGE;
+                last.clone()
+            }
+            ("<", Some(last)) => {
+                offset = offset.saturating_sub(CONSOLE_PAGE);
+                last.clone()
+            }
+            _ => {
+                offset = 0;
+                input
+            }
+        };
+        let (query, options) = SearchOptions::for_query(&input, folding);
+        if let Err(e) = options.query(query) {
+            let colored = print::colored(&io::stderr());
+            eprintln!("{}", print::paint("1;31", &e.to_string(), colored));
+            continue;
         }
-        struct Source<'a> {
-            ngram_file: &'a str,
-            index_file: &'a str,
-            text_file: &'a str,
+        let mut pages = pages(&dicts, &registry.lemmatizer, query, &options, offset);
+        if pages.iter().all(|page| page.total == 0) {
+            let suggestions = commands::suggest(&input, &dicts, folding);
+            if suggestions.is_empty() {
+                println!("{}", key("1;33", "見つかりませんでした"));
+            } else if auto_correct {
+                println!("{}を検索", key("1;32", &suggestions[0]));
+                // a headword is an ordinary query, searched as `input` is
+                pages = self::pages(&dicts, &registry.lemmatizer, &suggestions[0], &options, 0);
+            } else {
+                let words = suggestions
+                    .iter()
+                    .map(|s| key("1;32", s))
+                    .collect::<Vec<String>>();
+                println!("もしかして: {}", words.join(", "));
+            }
         }
-        for src in &[
-            &Source {
-                ngram_file: TANAKA_NGRAM,
-                index_file: TANAKA_INDEX,
-                text_file: TANAKA_TEXT,
-            },
-            &Source {
-                ngram_file: TED_NGRAM,
-                index_file: TED_INDEX,
-                text_file: TED_TEXT,
-            },
-        ] {
-            let nums = search::ngram_search(&input, &src.ngram_file, &src.index_file);
-            let hits = search::load_then_filter(&input, &nums, &src.text_file);
-            print::print_to_console(&input, hits);
+        for page in pages.iter().filter(|page| page.total > 0) {
+            print::print_to_console(page, &options);
         }
+        last = Some(input);
     }
 }
 
-fn edict_eiji(input: &String) {
-    let nums = search::ngram_search(&input, EDICT_NGRAM, EDICT_INDEX);
-    let mut hits = search::load_then_filter(&input, &nums, EDICT_TEXT);
-    if Path::new(EIJIRO_TEXT).exists() {
-        let nums = search::ngram_search(&input, EIJIRO_NGRAM, EIJIRO_INDEX);
-        let mut eiji_hits = search::load_then_filter(&input, &nums, EIJIRO_TEXT);
-        hits.append(&mut eiji_hits)
-    }
-    print::print_to_console(input, hits);
+/// One page of hits for every group of dictionaries sharing a display
+/// order, which are shown on one screen, ranked together and paged together,
+/// the hits of the base forms of the query last.
+fn pages(
+    dicts: &[&Dictionary],
+    lemmatizer: &Lemmatizer,
+    query: &str,
+    options: &SearchOptions,
+    offset: usize,
+) -> Vec<Page> {
+    dicts
+        .chunk_by(|a, b| a.order == b.order)
+        .map(|group| {
+            let options = SearchOptions {
+                offset,
+                limit: Some(CONSOLE_PAGE),
+                ..*options
+            };
+            lemmatizer.search_page(group, query, &options)
+        })
+        .collect()
 }
 
-fn get_input(prompt: &str) -> String {
+/// A line from stdin, or `None` once it is closed.
+fn get_input(prompt: &str) -> Option<String> {
     print!("{}", prompt);
     io::stdout().flush().unwrap();
     let mut input = String::new();
     match io::stdin().read_line(&mut input) {
+        Ok(0) => return None,
         Ok(_goes_into_input_above) => {}
         Err(_no_updates_is_fine) => {}
     }
-    input
+    let input = input
         .replace("\t", "📙")
         .replace(" ", "🍵")
         .trim()
         .replace("🍵", " ")
-        .replace("📙", "\t")
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::{
-        indexing::_setup_edict,
-        search::{load_then_filter, ngram_search},
-    };
-    use std::str::FromStr;
-
-    #[test]
-    fn journey_tests() {
-        _setup_edict();
-        {
-            let keyword = String::from_str("同型").unwrap();
-            let occurences = ngram_search(&keyword, super::EDICT_NGRAM, super::EDICT_INDEX);
-            assert_eq!(
-                occurences,
-                vec![(4455295, 102), (7362715, 82), (7364068, 82), (7364580, 78)]
-            );
-            let findings = load_then_filter(&keyword, &occurences, super::EDICT_TEXT);
-            assert_eq!(
-                findings,
-                vec![
-                    "isomorphism\t/aɪsoʊmɔːfɪzəm/aisoùm<ħ>ò</ħ>fizøm/ 同型 [どうけい],同形 [どうけい]",
-                    "same pattern\t/sɛɪm pætn/seim pätn/ 同型 [どうけい],同形 [どうけい]",
-                    "same shape\t/sɛɪm ʃɛɪp/seim ŝeip/ 同型 [どうけい],同形 [どうけい]",
-                    "same type\t/sɛɪm taɪp/seim taip/ 同型 [どうけい],同形 [どうけい]"
-                ]
-            );
-        }
-        {
-            let keyword = String::from_str("🍁").unwrap();
-            let occurences = ngram_search(&keyword, super::EDICT_NGRAM, super::EDICT_INDEX);
-            assert_eq!(occurences, vec![]);
-            let findings = load_then_filter(&keyword, &occurences, super::EDICT_TEXT);
-            assert_eq!(findings, Vec::<String>::new());
-        }
-        {
-            let keyword = String::from_str("!").unwrap();
-            let occurences = ngram_search(&keyword, super::EDICT_NGRAM, super::EDICT_INDEX);
-            assert_eq!(occurences.len(), 96);
-            let findings = load_then_filter(&keyword, &occurences, super::EDICT_TEXT);
-            assert_eq!(findings[0], "a happy new year!\t/ə hæpiː njuː -/ø häp<ħ>ï</ħ> nj<ħ>u</ħ> -/ 賀正 [がしょう],賀正 [がせい]");
-            assert_eq!(findings[95], "yuck!\t/-/-/ 最低 [さいてい]");
-        }
-        {
-            let keyword = String::from_str("\t\t\t!").unwrap();
-            let occurences = ngram_search(&keyword, super::EDICT_NGRAM, super::EDICT_INDEX);
-            assert_eq!(occurences, vec![]);
-            let findings = load_then_filter(&keyword, &occurences, super::EDICT_TEXT);
-            assert_eq!(findings, Vec::<String>::new());
-        }
-    }
+        .replace("📙", "\t");
+    Some(input)
 }
@@ -0,0 +1,138 @@
+use serde::Deserialize;
+use unicode_normalization::char::canonical_combining_class;
+use unicode_normalization::UnicodeNormalization;
+
+/// How the text of a normalized index and the keywords searched in it are
+/// folded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Folding {
+    /// Exact bytes, there is no normalized index.
+    #[default]
+    None = 0,
+    /// NFKC, which also folds full-width and half-width forms, then lowercase.
+    Nfkc = 1,
+    /// As `Nfkc`, with katakana folded into hiragana.
+    Kana = 2,
+}
+
+impl Folding {
+    pub fn of(flag: u8) -> Folding {
+        match flag {
+            1 => Folding::Nfkc,
+            2 => Folding::Kana,
+            _ => Folding::None,
+        }
+    }
+}
+
+pub fn normalize(text: &str, folding: Folding) -> String {
+    if folding == Folding::None {
+        return text.to_string();
+    }
+    normalize_mapped(text, folding).0
+}
+
+/// Normalizes the text and maps every byte of the result back to the byte
+/// range of the original characters it came from.
+fn normalize_mapped(text: &str, folding: Folding) -> (String, Vec<(usize, usize)>) {
+    let mut normalized = String::with_capacity(text.len());
+    let mut map = Vec::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        // combining marks are normalized together with their base character
+        let mut end = start + c.len_utf8();
+        while let Some(&(p, next)) = chars.peek() {
+            if !is_combining(next) {
+                break;
+            }
+            end = p + next.len_utf8();
+            chars.next();
+        }
+        for c in text[start..end].nfkc().flat_map(char::to_lowercase) {
+            normalized.push(fold_kana(c, folding));
+        }
+        map.resize(normalized.len(), (start, end));
+    }
+    (normalized, map)
+}
+
+/// Byte spans of the text whose normalized form matches the normalized
+/// keyword, or which match the keyword exactly with `Folding::None`.
+pub fn find_spans(text: &str, keyword: &str, folding: Folding) -> Vec<(usize, usize)> {
+    let keyword = normalize(keyword, folding);
+    if keyword.is_empty() {
+        return vec![];
+    }
+    let (normalized, map) = match folding {
+        Folding::None => (text.to_string(), vec![]),
+        _ => normalize_mapped(text, folding),
+    };
+    let mut spans: Vec<(usize, usize)> = vec![];
+    for (i, _) in normalized.match_indices(&keyword) {
+        let span = match folding {
+            Folding::None => (i, i + keyword.len()),
+            _ => (map[i].0, map[i + keyword.len() - 1].1),
+        };
+        match spans.last_mut() {
+            Some(last) if span.0 < last.1 => last.1 = last.1.max(span.1),
+            _ => spans.push(span),
+        }
+    }
+    spans
+}
+
+/// Sorts the spans and merges those that overlap.
+pub fn merge_spans(mut found: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    found.sort();
+    let mut spans: Vec<(usize, usize)> = vec![];
+    for span in found {
+        match spans.last_mut() {
+            Some(last) if span.0 < last.1 => last.1 = last.1.max(span.1),
+            _ => spans.push(span),
+        }
+    }
+    spans
+}
+
+fn is_combining(c: char) -> bool {
+    // half-width voiced sound marks are spacing characters until NFKC
+    canonical_combining_class(c) != 0 || c == '\u{FF9E}' || c == '\u{FF9F}'
+}
+
+fn fold_kana(c: char, folding: Folding) -> char {
+    match c {
+        'ァ'..='ヶ' | 'ヽ' | 'ヾ' if folding == Folding::Kana => {
+            char::from_u32(c as u32 - 0x60).unwrap()
+        }
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_spans, normalize, Folding};
+
+    #[test]
+    fn folds_width_case_and_kana() {
+        assert_eq!(
+            normalize("ＡＢＣ Isomorphism", Folding::Nfkc),
+            "abc isomorphism"
+        );
+        assert_eq!(normalize("ドウケイ", Folding::Nfkc), "ドウケイ");
+        assert_eq!(normalize("ドウケイ", Folding::Kana), "どうけい");
+        assert_eq!(normalize("ｶﾞｲｺｸ", Folding::Kana), "がいこく");
+        assert_eq!(normalize("ＡＢＣ", Folding::None), "ＡＢＣ");
+    }
+
+    #[test]
+    fn maps_spans_back_to_the_original_text() {
+        let line = "ＡＢＣ\t同型 [どうけい]";
+        assert_eq!(find_spans(line, "abc", Folding::Nfkc), vec![(0, 9)]);
+        assert_eq!(find_spans(line, "ドウ", Folding::Kana), vec![(18, 24)]);
+        assert_eq!(find_spans("ｶﾞｲ", "が", Folding::Kana), vec![(0, 6)]);
+        assert_eq!(find_spans(line, "xyz", Folding::Kana), vec![]);
+        assert_eq!(find_spans("a-a", "a", Folding::None), vec![(0, 1), (2, 3)]);
+        assert_eq!(find_spans(line, "abc", Folding::None), vec![]);
+    }
+}
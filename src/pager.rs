@@ -0,0 +1,314 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::process::{self, Command, Stdio};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, execute, queue};
+use unicode_width::UnicodeWidthStr;
+
+const LESS: [&str; 5] = ["less", "-R", "-M", "+Gg", "-s"];
+
+/// Set for the pager unless the user set them, as git does, so that `less`
+/// and `lv` show colors instead of escape codes.
+const PAGER_ENV: [(&str, &str); 2] = [("LESS", "R"), ("LV", "-c")];
+
+/// Shows the text through `$PAGER`, then `less`, then the built-in pager,
+/// whichever starts first. Writes it as it is when stdout is not a terminal
+/// or the built-in pager fails.
+pub fn page(text: &str) {
+    if !io::stdout().is_terminal() {
+        println!("{}", text);
+        return;
+    }
+    if let Ok(pager) = env::var("PAGER") {
+        let command = pager.split_whitespace().collect::<Vec<&str>>();
+        if !command.is_empty() && spawn(&command, text) {
+            return;
+        }
+    }
+    if !spawn(&LESS, text) {
+        if let Err(e) = builtin(text) {
+            warn!("built-in pager: {}", e);
+            println!("{}", text);
+        }
+    }
+}
+
+/// The command line of an external pager with its environment.
+fn pager(command: &[&str]) -> Command {
+    let mut pager = Command::new(command[0]);
+    pager.args(&command[1..]);
+    for (var, value) in PAGER_ENV {
+        if env::var_os(var).is_none() {
+            pager.env(var, value);
+        }
+    }
+    pager
+}
+
+/// Pipes the text into an external pager. Tells whether it could be started.
+fn spawn(command: &[&str], text: &str) -> bool {
+    let mut child = match pager(command).stdin(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            info!("cannot start {}: {}", command[0], e);
+            return false;
+        }
+    };
+    // the pager may quit before reading everything
+    child.stdin.take().unwrap().write_all(text.as_bytes()).ok();
+    if let Err(e) = child.wait() {
+        warn!("{}: {}", command[0], e);
+    }
+    true
+}
+
+/// Restores the terminal however the built-in pager ends.
+struct Screen;
+
+impl Screen {
+    fn enter() -> io::Result<Screen> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+        Ok(Screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen).ok();
+        terminal::disable_raw_mode().ok();
+    }
+}
+
+/// A scrolling pager with the keys of `less` the banner mentions: `d`/`u`
+/// scroll half a screen, space/`b` a screen, `j`/`k` a line, `g`/`G` to the
+/// ends, `/` searches, `n`/`N` repeat the search and `q` quits.
+fn builtin(text: &str) -> io::Result<()> {
+    let lines = text.split('\n').collect::<Vec<&str>>();
+    let plain = lines.iter().map(|l| visible(l)).collect::<Vec<String>>();
+    let screen = Screen::enter()?;
+    let mut out = io::stdout();
+    let mut top = 0;
+    let mut pattern = String::new();
+    let mut message = String::new();
+    loop {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width.max(1) as usize, height.max(2) as usize - 1);
+        let rows = plain.iter().map(|l| rows(l, width)).collect::<Vec<usize>>();
+        let max_top = last_page(&rows, height);
+        top = top.min(max_top);
+        let shown = draw(&mut out, &lines, &rows, top, height)?;
+        let status = match message.as_str() {
+            "" => format!(
+                "lines {}-{}/{} {}%",
+                top + 1,
+                top + shown,
+                lines.len(),
+                (top + shown) * 100 / lines.len().max(1)
+            ),
+            m => m.to_string(),
+        };
+        status_line(&mut out, height, &status)?;
+        message.clear();
+
+        let key = match event::read()? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue,
+        };
+        let half = (shown / 2).max(1);
+        match key {
+            KeyEvent {
+                code: KeyCode::Char('c'),
+                modifiers: KeyModifiers::CONTROL,
+                ..
+            } => {
+                drop(screen);
+                process::exit(130);
+            }
+            KeyEvent { code, .. } => match code {
+                KeyCode::Char('q') | KeyCode::Char('Q') | KeyCode::Esc => break,
+                KeyCode::Char('d') => top += half,
+                KeyCode::Char('u') => top = top.saturating_sub(half),
+                KeyCode::Char(' ') | KeyCode::Char('f') | KeyCode::PageDown => top += shown.max(1),
+                KeyCode::Char('b') | KeyCode::PageUp => top = top.saturating_sub(shown.max(1)),
+                KeyCode::Char('j') | KeyCode::Enter | KeyCode::Down => top += 1,
+                KeyCode::Char('k') | KeyCode::Up => top = top.saturating_sub(1),
+                KeyCode::Char('g') | KeyCode::Home => top = 0,
+                KeyCode::Char('G') | KeyCode::End => top = max_top,
+                KeyCode::Char('/') => {
+                    if let Some(p) = prompt(&mut out, height)? {
+                        pattern = p;
+                        message = search(&plain, &pattern, &mut top, true);
+                    }
+                }
+                KeyCode::Char('n') => message = search(&plain, &pattern, &mut top, true),
+                KeyCode::Char('N') => message = search(&plain, &pattern, &mut top, false),
+                _ => {}
+            },
+        }
+        top = top.min(max_top);
+    }
+    Ok(())
+}
+
+/// Draws the lines from `top` that fit in `height` rows and tells how many
+/// lines were drawn. A line taller than the screen is drawn anyway.
+fn draw(
+    out: &mut impl Write,
+    lines: &[&str],
+    rows: &[usize],
+    top: usize,
+    height: usize,
+) -> io::Result<usize> {
+    queue!(out, terminal::Clear(ClearType::All))?;
+    let mut row = 0;
+    let mut shown = 0;
+    for (line, &r) in lines.iter().zip(rows).skip(top) {
+        if shown > 0 && row + r > height {
+            break;
+        }
+        queue!(
+            out,
+            cursor::MoveTo(0, row as u16),
+            Print(line),
+            Print("\x1b[0m")
+        )?;
+        row += r;
+        shown += 1;
+    }
+    out.flush()?;
+    Ok(shown)
+}
+
+fn status_line(out: &mut impl Write, height: usize, status: &str) -> io::Result<()> {
+    queue!(
+        out,
+        cursor::MoveTo(0, height as u16),
+        terminal::Clear(ClearType::CurrentLine),
+        SetAttribute(Attribute::Reverse),
+        Print(status),
+        SetAttribute(Attribute::Reset)
+    )?;
+    out.flush()
+}
+
+/// Reads a search pattern on the status line. `None` when cancelled.
+fn prompt(out: &mut impl Write, height: usize) -> io::Result<Option<String>> {
+    let mut pattern = String::new();
+    loop {
+        queue!(
+            out,
+            cursor::MoveTo(0, height as u16),
+            terminal::Clear(ClearType::CurrentLine),
+            Print("/"),
+            Print(&pattern)
+        )?;
+        out.flush()?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+            match key.code {
+                KeyCode::Enter => return Ok(Some(pattern)),
+                KeyCode::Esc => return Ok(None),
+                KeyCode::Backspace if pattern.pop().is_none() => return Ok(None),
+                KeyCode::Char(c) => pattern.push(c),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Moves `top` to the next line containing the pattern, after the first line
+/// on screen, or to the previous one. Returns the message to show.
+fn search(plain: &[String], pattern: &str, top: &mut usize, forward: bool) -> String {
+    if pattern.is_empty() {
+        return "no previous search".to_string();
+    }
+    let found = if forward {
+        (*top + 1..plain.len()).find(|&i| plain[i].contains(pattern))
+    } else {
+        (0..*top).rev().find(|&i| plain[i].contains(pattern))
+    };
+    match found {
+        Some(i) => {
+            *top = i;
+            String::new()
+        }
+        None => "Pattern not found".to_string(),
+    }
+}
+
+/// The first line to show so that the last line ends at the bottom.
+fn last_page(rows: &[usize], height: usize) -> usize {
+    let mut used = 0;
+    for (i, &r) in rows.iter().enumerate().rev() {
+        used += r;
+        if used > height {
+            return (i + 1).min(rows.len() - 1);
+        }
+    }
+    0
+}
+
+/// Number of terminal rows a line takes once wrapped.
+fn rows(plain: &str, width: usize) -> usize {
+    plain.width().div_ceil(width).max(1)
+}
+
+/// The line without its escape sequences.
+pub fn visible(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        // CSI sequences end with a byte in @..~
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{last_page, pager, rows, visible};
+    use std::env;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn measures_lines_without_escapes() {
+        let line = "\x1b[1;36mdog\x1b[0m  \x1b[9m犬\x1b[0m";
+        assert_eq!(visible(line), "dog  犬");
+        assert_eq!(rows(&visible(line), 80), 1);
+        assert_eq!(rows("犬犬犬", 4), 2);
+        assert_eq!(rows("", 80), 1);
+        assert_eq!(last_page(&[1, 1, 1, 1], 2), 2);
+        assert_eq!(last_page(&[1, 3], 2), 1);
+        assert_eq!(last_page(&[1], 5), 0);
+    }
+
+    #[test]
+    fn lets_less_show_colors() {
+        let pager = pager(&["less", "-M"]);
+        let less = pager
+            .get_envs()
+            .find(|(var, _)| *var == OsStr::new("LESS"))
+            .and_then(|(_, value)| value);
+        match env::var_os("LESS") {
+            None => assert_eq!(less, Some(OsStr::new("R"))),
+            Some(_) => assert_eq!(less, None),
+        }
+        assert_eq!(pager.get_args().collect::<Vec<_>>(), ["-M"]);
+    }
+}
@@ -1,67 +1,307 @@
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::env;
+use std::ffi::OsStr;
+use std::io::{self, IsTerminal};
+use std::sync::OnceLock;
 
-pub fn print_to_console(input: &String, hits: Vec<String>) {
-    print_results(decorate(&input, hits));
+use clap::ValueEnum;
+use eiji_sub_ngram::{Page, SearchHit, SearchOptions};
+
+use crate::pager;
+
+/// When escape sequences are written.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColorMode {
+    /// When writing to a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+static COLOR: OnceLock<ColorMode> = OnceLock::new();
+
+pub fn set_color(mode: ColorMode) {
+    COLOR.set(mode).ok();
+}
+
+impl ColorMode {
+    /// Whether to color, given the value of `NO_COLOR` and whether the output
+    /// is a terminal. An empty `NO_COLOR` counts as unset.
+    fn colors(self, no_color: Option<&OsStr>, terminal: bool) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => no_color.is_none_or(OsStr::is_empty) && terminal,
+        }
+    }
+}
+
+/// Whether what goes to the stream is colored.
+pub fn colored(stream: &impl IsTerminal) -> bool {
+    let mode = COLOR.get().copied().unwrap_or_default();
+    mode.colors(env::var_os("NO_COLOR").as_deref(), stream.is_terminal())
+}
+
+/// Wraps the text in the SGR parameters `style`, such as `1;32`, if colored.
+pub fn paint(style: &str, text: &str, colored: bool) -> String {
+    if colored {
+        format!("\x1b[{}m{}\x1b[0m", style, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Pages the hits under a `showing N of M` line, highlighting what they
+/// matched, the base form for the hits found via one. The hits of a wildcard
+/// pattern come under the length of their headword.
+pub fn print_to_console(page: &Page, options: &SearchOptions) {
+    let colored = colored(&io::stdout());
+    let mut results = vec![paint("1;33", &page.summary(), colored)];
+    let mut length = None;
+    for hit in &page.hits {
+        let chars = hit.headword.chars().count();
+        if options.wildcard && length != Some(chars) {
+            results.push(paint("1;33", &format!("── {} ──", chars), colored));
+            length = Some(chars);
+        }
+        let mut line = render(&hit.line(), &hit.line_spans(), colored);
+        if let Some(lemma) = &hit.lemma {
+            line.push_str("  ");
+            line.push_str(&paint("1;35", &label(lemma), colored));
+        }
+        results.push(line);
+    }
+    print_results(results)
 }
 
-fn reorder<'a>(hits: &Vec<&'a str>, input: &String) -> Vec<&'a str> {
-    let mut a = vec![];
-    let mut b = vec![];
-    for i in 0..hits.len() {
-        if hits[i].starts_with(input.as_str()) {
-            a.push(hits[i])
+/// A hit as a plain `headword<TAB>body` line for scripts, without colors,
+/// labeled after another tab when it was found via a base form of the query.
+pub fn plain(hit: &SearchHit) -> String {
+    match &hit.lemma {
+        Some(lemma) => format!("{}\t{}", unmark(&hit.line()), label(lemma)),
+        None => unmark(&hit.line()),
+    }
+}
+
+/// Tells a hit was found via a base form of the query.
+fn label(lemma: &str) -> String {
+    format!("(found via lemma {})", lemma)
+}
+
+/// Renders the `<ħ>` markup of the dictionaries as `~x~` for plain text.
+fn unmark(text: &str) -> String {
+    text.replace("<ħ>", "~").replace("</ħ>", "~")
+}
+
+/// The text without its `<ħ>` tags and with the `\n` escapes turned into
+/// line breaks, along with the byte ranges of that text the tags enclosed and
+/// where every byte offset of the original text, and its end, ends up.
+fn unescape(text: &str) -> (String, Vec<(usize, usize)>, Vec<usize>) {
+    let mut out = String::with_capacity(text.len());
+    let mut struck = vec![];
+    let mut at = Vec::with_capacity(text.len() + 1);
+    let mut open = None;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let (before, left) = (out.len(), rest.len());
+        if let Some(r) = rest.strip_prefix("<ħ>") {
+            open = Some(out.len());
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("</ħ>") {
+            struck.extend(open.take().map(|start| (start, out.len())));
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("\\n") {
+            out.push('\n');
+            rest = r;
         } else {
-            b.push(hits[i])
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
         }
+        // the rest of a tag, an escape or a character is past what it became
+        at.push(before);
+        at.extend(std::iter::repeat_n(out.len(), left - rest.len() - 1));
     }
-    a.append(&mut b);
-    a
+    at.push(out.len());
+    (out, struck, at)
 }
 
-fn decorate(input: &String, hits: Vec<String>) -> Vec<String> {
-    let high_light_left = format!(
-        "\x1b[0m\x1b[1;32m{}\x1b[0m\x1b[1;36m",
-        input.replace("\t", "")
-    );
-    let high_light_right = format!("\x1b[1;32m{}\x1b[0m", input);
-    reorder(&hits.iter().map(|s| s.as_str()).collect(), &input)
+/// Renders a `headword<TAB>body` line for the console, highlighting the
+/// spans of the line a hit matched. The spans are moved onto the text without
+/// its tags and the escape sequences, or the `~` marks of `<ħ>` without
+/// colors, are only added afterwards, so nothing is highlighted inside them.
+fn render(line: &str, spans: &[(usize, usize)], colored: bool) -> String {
+    let (text, struck, at) = unescape(line);
+    let matched = spans
         .iter()
-        .map(|l| {
-            let tabi = l.find('\t').unwrap();
-            let left = &l[0..tabi];
-            let right = &l[tabi + 1..];
-            format!(
-                "\x1b[1;36m{}\x1b[0m  {}",
-                left.replace(&input.replace("\t", ""), &high_light_left),
-                right
-                    .replace("\\n", "\n")
-                    .replace("<ħ>", "\x1b[9m")
-                    .replace("</ħ>", "\x1b[0m")
-                    .replace(input.as_str(), &high_light_right)
-            )
-        })
-        .collect::<Vec<String>>()
+        .map(|&(s, e)| (at[s], at[e]))
+        .collect::<Vec<_>>();
+    let tab = text.find('\t').unwrap_or(text.len());
+    let inside = |spans: &[(usize, usize)], i: usize| spans.iter().any(|&(s, e)| s <= i && i < e);
+    let style = |i: usize| {
+        let mut sgr = vec![];
+        if inside(&matched, i) {
+            sgr.push("1;32");
+        } else if i < tab {
+            sgr.push("1;36");
+        }
+        if inside(&struck, i) {
+            sgr.push("9");
+        }
+        sgr.join(";")
+    };
+    let mut out = String::with_capacity(text.len() * 2);
+    let mut current = String::new();
+    for (i, c) in text.char_indices() {
+        if colored {
+            let next = style(i);
+            if next != current {
+                out.push_str("\x1b[0m");
+                if !next.is_empty() {
+                    out.push_str(&format!("\x1b[{}m", next));
+                }
+                current = next;
+            }
+        } else {
+            let marks = struck.iter().filter(|&&(s, e)| s == i || e == i).count();
+            out.extend(std::iter::repeat_n('~', marks));
+        }
+        match c {
+            '\t' if i == tab => out.push_str("  "),
+            c => out.push(c),
+        }
+    }
+    if colored && !current.is_empty() {
+        out.push_str("\x1b[0m");
+    } else if !colored {
+        let marks = struck.iter().filter(|&&(_, e)| e == text.len()).count();
+        out.extend(std::iter::repeat_n('~', marks));
+    }
+    out
 }
 
 fn print_results(results: Vec<String>) {
-    let mut child = Command::new("less")
-        .arg("-R")
-        .arg("-M")
-        .arg("+Gg")
-        .arg("-s")
-        .stdin(Stdio::piped())
-        .spawn()
-        .unwrap();
-    if child
-        .stdin
-        .as_mut()
-        .ok_or("Child process stdin has not been captured!")
-        .unwrap()
-        .write_all(results.join("\n").as_bytes())
-        .is_err()
-    {}
-    if let Err(why) = child.wait() {
-        panic!("{}", why)
+    pager::page(&results.join("\n"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unescape, ColorMode};
+    use crate::pager::visible;
+    use eiji_sub_ngram::{Folding, Query, SearchHit};
+    use std::ffi::OsStr;
+
+    const LINE: &str = "isomorphism\t/aisoùm<ħ>ò</ħ>fizøm/ 1;36 同型\\nm";
+
+    /// The line rendered with what the query matches in it highlighted.
+    fn render(line: &str, query: &Query, folding: Folding, colored: bool) -> String {
+        super::render(line, &query.spans(line, folding), colored)
+    }
+
+    /// Every escape sequence is a complete SGR sequence of digits and `;`.
+    fn well_formed(out: &str) -> bool {
+        out.split('\x1b').skip(1).all(|seq| {
+            let end = seq.find('m').unwrap_or(seq.len());
+            seq.starts_with('[')
+                && end < seq.len()
+                && seq[1..end].chars().all(|c| c.is_ascii_digit() || c == ';')
+        })
+    }
+
+    #[test]
+    fn colors_terminals_unless_no_color_is_set() {
+        let set = Some(OsStr::new("1"));
+        let empty = Some(OsStr::new(""));
+        assert!(ColorMode::Auto.colors(None, true));
+        assert!(!ColorMode::Auto.colors(None, false));
+        assert!(!ColorMode::Auto.colors(set, true));
+        assert!(ColorMode::Auto.colors(empty, true));
+        assert!(!ColorMode::Auto.colors(empty, false));
+        // --color overrides both
+        assert!(ColorMode::Always.colors(set, false));
+        assert!(!ColorMode::Never.colors(None, true));
+    }
+
+    #[test]
+    fn strips_tags_and_escapes() {
+        let (text, struck, at) = unescape(LINE);
+        assert_eq!(text, "isomorphism\t/aisoùmòfizøm/ 1;36 同型\nm");
+        assert_eq!(&text[struck[0].0..struck[0].1], "ò");
+        assert_eq!(at.len(), LINE.len() + 1);
+        let moved = |s: &str| {
+            let start = LINE.find(s).unwrap();
+            &text[at[start]..at[start + s.len()]]
+        };
+        assert_eq!(moved("m<ħ>ò</ħ>f"), "mòf");
+        assert_eq!(moved("同型\\nm"), "同型\nm");
+        assert_eq!(moved("<ħ>"), "");
+    }
+
+    #[test]
+    fn queries_never_match_inside_escape_codes() {
+        for query in ["m", "1;3", "1;36", "[", "\x1b", "0m", "9", ";"] {
+            let out = render(LINE, &Query::literal(query), Folding::None, true);
+            assert!(well_formed(&out), "{:?} gave {:?}", query, out);
+            assert_eq!(
+                visible(&out),
+                "isomorphism  /aisoùmòfizøm/ 1;36 同型\nm",
+                "{:?}",
+                query
+            );
+        }
+        let out = render(LINE, &Query::literal("1;36"), Folding::None, true);
+        assert!(out.contains("\x1b[0m\x1b[1;32m1;36\x1b[0m"));
+    }
+
+    #[test]
+    fn queries_never_match_inside_tags() {
+        for query in ["ħ", "<ħ>", "</", "<"] {
+            let out = render(LINE, &Query::literal(query), Folding::None, true);
+            assert!(!out.contains("1;32"), "{:?} gave {:?}", query, out);
+            assert!(out.contains("\x1b[0m\x1b[9mò\x1b[0m"));
+        }
+        // a match across a tag highlights the struck character too
+        let out = render(LINE, &Query::literal("m<ħ>ò</ħ>f"), Folding::None, true);
+        assert!(out.contains("\x1b[0m\x1b[1;32;9mò\x1b[0m\x1b[1;32mf"));
+    }
+
+    #[test]
+    fn highlights_headword_and_body_alike() {
+        let out = render(LINE, &Query::literal("morph"), Folding::None, true);
+        assert!(out.starts_with("\x1b[0m\x1b[1;36miso\x1b[0m\x1b[1;32mmorph\x1b[0m\x1b[1;36mism"));
+        let out = render(LINE, &Query::literal("ism\t/ai"), Folding::None, true);
+        assert!(out.contains("\x1b[1;32mism  /ai\x1b[0m"));
+        let out = render("ＩＳＯ\tiso", &Query::literal("iso"), Folding::Nfkc, true);
+        assert_eq!(visible(&out), "ＩＳＯ  iso");
+        assert_eq!(out.matches("1;32").count(), 2);
+    }
+
+    #[test]
+    fn renders_plain_text_without_color() {
+        let out = render(LINE, &Query::literal("m"), Folding::None, false);
+        assert_eq!(out, "isomorphism  /aisoùm~ò~fizøm/ 1;36 同型\nm");
+        assert_eq!(
+            render("a\t<ħ>b</ħ>", &Query::literal("b"), Folding::None, false),
+            "a  ~b~"
+        );
+    }
+
+    #[test]
+    fn highlights_every_term() {
+        let out = render(LINE, &Query::parse("iso 同型"), Folding::None, true);
+        assert!(out.contains("\x1b[1;32miso\x1b[0m"));
+        assert!(out.contains("\x1b[1;32m同型\x1b[0m"));
+        let out = render(LINE, &Query::regex("^iso|m$").unwrap(), Folding::None, true);
+        assert!(out.starts_with("\x1b[0m\x1b[1;32miso\x1b[0m\x1b[1;36mmorphism"));
+        assert!(out.ends_with("\n\x1b[0m\x1b[1;32mm\x1b[0m"));
+    }
+
+    #[test]
+    fn highlights_what_the_hit_matched() {
+        // found via the lemma `run` of `ran`, which is not in the line
+        let line = "run\t走る, ran";
+        let hit = SearchHit::new("verbs", 0, line, &Query::literal("run"), Folding::None);
+        let out = super::render(&hit.line(), &hit.line_spans(), true);
+        assert!(out.starts_with("\x1b[0m\x1b[1;32mrun\x1b[0m"));
+        assert_eq!(out.matches("1;32").count(), 1);
     }
 }
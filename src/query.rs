@@ -0,0 +1,455 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use regex::Regex;
+use regex_syntax::hir::{Hir, HirKind};
+
+use crate::normalize::{find_spans, merge_spans, normalize, Folding};
+
+/// A word or a quoted phrase of a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Term {
+    pub text: String,
+    /// Written `-term`, the line must not contain it.
+    pub negated: bool,
+    pub field: Field,
+}
+
+/// The part of a `headword<TAB>body` line a term is looked for in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Field {
+    /// The whole line, the tab included.
+    #[default]
+    Any,
+    /// Written `hw:term`, the text before the tab.
+    Headword,
+    /// Written `body:term`, the text after the tab.
+    Body,
+}
+
+impl Field {
+    const PREFIXES: [(&'static str, Field); 2] = [("hw:", Field::Headword), ("body:", Field::Body)];
+
+    /// The part of the line in this field.
+    fn of<'a>(&self, line: &'a str) -> &'a str {
+        match (self, line.split_once('\t')) {
+            (Field::Any, _) => line,
+            (Field::Headword, Some((headword, _))) => headword,
+            (Field::Body, Some((_, body))) => body,
+            (Field::Headword, None) => line,
+            (Field::Body, None) => "",
+        }
+    }
+
+    /// Whether the span of a line lies in this field.
+    fn holds(&self, line: &str, (start, end): (usize, usize)) -> bool {
+        let tab = line.find('\t');
+        match self {
+            Field::Any => true,
+            Field::Headword => end <= tab.unwrap_or(line.len()),
+            Field::Body => tab.is_some_and(|tab| start > tab),
+        }
+    }
+}
+
+/// A query of terms separated by spaces, which must all match, or by `|`,
+/// of which one side must match. `-term` excludes the lines containing the
+/// term and `"a phrase"` matches the words together, spaces included.
+/// `hw:term` only matches headwords and `body:term` only translations.
+///
+/// `a b | c -d` matches the lines containing both `a` and `b`, and those
+/// containing `c` but not `d`.
+///
+/// A query can also be a regular expression, see `Query::regex`.
+#[derive(Debug, Clone)]
+pub struct Query {
+    /// Conjunctions of terms, any of which matches. Every one of them has a
+    /// term which is not negated, to look up in the index. Empty for a
+    /// regular expression.
+    pub alternatives: Vec<Vec<Term>>,
+    regex: Option<Regex>,
+    /// Literals every match of the regular expression contains.
+    fragments: Vec<String>,
+    /// What the headwords a wildcard pattern matches start with.
+    head: String,
+}
+
+impl Query {
+    /// Parses the query. When nothing is left to look up, such as for `-a`,
+    /// the whole input is searched literally instead.
+    pub fn parse(input: &str) -> Query {
+        let mut alternatives = vec![];
+        let mut terms = vec![];
+        let mut chars = input.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            match c {
+                ' ' => {
+                    chars.next();
+                }
+                '|' => {
+                    chars.next();
+                    alternatives.push(std::mem::take(&mut terms));
+                }
+                _ => {
+                    let mut negated = false;
+                    if c == '-' {
+                        chars.next();
+                        match chars.peek() {
+                            Some(&n) if n != ' ' && n != '|' => negated = true,
+                            // a lone `-` is a word
+                            _ => {
+                                terms.push(Term::new("-", false));
+                                continue;
+                            }
+                        }
+                    }
+                    let field = scope(&mut chars);
+                    let mut text = String::new();
+                    if chars.peek() == Some(&'"') {
+                        chars.next();
+                        // an unclosed quote runs to the end
+                        text.extend(chars.by_ref().take_while(|&c| c != '"'));
+                    } else {
+                        while let Some(&c) = chars.peek() {
+                            if c == ' ' || c == '|' {
+                                break;
+                            }
+                            text.push(c);
+                            chars.next();
+                        }
+                    }
+                    if !text.is_empty() {
+                        terms.push(Term {
+                            text,
+                            negated,
+                            field,
+                        });
+                    }
+                }
+            }
+        }
+        alternatives.push(terms);
+        alternatives.retain(|terms| terms.iter().any(|t| !t.negated));
+        if alternatives.is_empty() {
+            return Query::literal(input);
+        }
+        Query::of(alternatives)
+    }
+
+    /// The input as a single term, as for a headword prefix search.
+    pub fn literal(input: &str) -> Query {
+        Query::of(vec![vec![Term::new(input, false)]])
+    }
+
+    fn of(alternatives: Vec<Vec<Term>>) -> Query {
+        Query {
+            alternatives,
+            regex: None,
+            fragments: vec![],
+            head: String::new(),
+        }
+    }
+
+    /// A regular expression matched against the whole line, such as
+    /// `^un.*able\t` for the headwords starting with `un` and ending with
+    /// `able`.
+    pub fn regex(pattern: &str) -> Result<Query, regex::Error> {
+        let regex = Regex::new(pattern)?;
+        // a pattern Regex accepts parses
+        let hir = regex_syntax::parse(pattern).unwrap();
+        let mut fragments = vec![];
+        required(&hir, &mut fragments);
+        Ok(Query {
+            alternatives: vec![],
+            regex: Some(regex),
+            fragments,
+            head: String::new(),
+        })
+    }
+
+    /// A headword pattern where `?` stands for exactly one character and `*`
+    /// for any run of them, such as `c?t` or `*ness`.
+    pub fn wildcard(pattern: &str) -> Query {
+        let mut regex = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '?' => regex.push_str("[^\\t]"),
+                '*' => regex.push_str("[^\\t]*"),
+                c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        regex.push_str("\\t");
+        // every character but the wildcards is escaped
+        let mut query = Query::regex(&regex).unwrap();
+        query.head = pattern
+            .chars()
+            .take_while(|&c| c != '?' && c != '*')
+            .collect();
+        query
+    }
+
+    /// Whether the text after the leading `~` which asks for a wildcard
+    /// search is a pattern: a single word which is not only `*`.
+    pub fn is_wildcard(pattern: &str) -> bool {
+        !pattern.contains([' ', '\t']) && pattern.chars().any(|c| c != '*')
+    }
+
+    pub fn is_regex(&self) -> bool {
+        self.regex.is_some()
+    }
+
+    /// What the headwords matching a wildcard pattern start with, empty
+    /// otherwise.
+    pub fn head(&self) -> &str {
+        &self.head
+    }
+
+    /// Literals which every line matching the regular expression contains,
+    /// to look up in the index. Empty when there are none or this is not a
+    /// regular expression.
+    pub fn fragments(&self) -> &[String] {
+        &self.fragments
+    }
+
+    /// The terms to look up and to highlight, those which are not negated.
+    pub fn terms(&self) -> Vec<&str> {
+        let mut terms = vec![];
+        for term in self.alternatives.iter().flatten() {
+            if !term.negated && !terms.contains(&term.text.as_str()) {
+                terms.push(term.text.as_str());
+            }
+        }
+        terms
+    }
+
+    /// The query with every term folded as the lines are by `matches`. A
+    /// regular expression is left as it is.
+    pub fn normalized(&self, folding: Folding) -> Query {
+        if self.is_regex() {
+            return self.clone();
+        }
+        let alternatives = self
+            .alternatives
+            .iter()
+            .map(|terms| {
+                terms
+                    .iter()
+                    .map(|t| Term {
+                        text: normalize(&t.text, folding),
+                        ..*t
+                    })
+                    .collect()
+            })
+            .collect();
+        Query::of(alternatives)
+    }
+
+    /// Whether the line, normalized as the query was, matches.
+    pub fn matches(&self, line: &str) -> bool {
+        if let Some(regex) = &self.regex {
+            return regex.is_match(line);
+        }
+        self.alternatives.iter().any(|terms| {
+            terms
+                .iter()
+                .all(|t| t.field.of(line).contains(t.text.as_str()) != t.negated)
+        })
+    }
+
+    /// Byte spans of the text to highlight: the matches of the regular
+    /// expression, or of the terms folded this way.
+    pub fn spans(&self, text: &str, folding: Folding) -> Vec<(usize, usize)> {
+        match &self.regex {
+            Some(regex) => regex
+                .find_iter(text)
+                .filter(|m| !m.is_empty())
+                .map(|m| (m.start(), m.end()))
+                .collect(),
+            None => {
+                let mut spans = vec![];
+                for term in self.alternatives.iter().flatten() {
+                    if !term.negated {
+                        let found = find_spans(text, &term.text, folding);
+                        spans.extend(found.into_iter().filter(|&s| term.field.holds(text, s)));
+                    }
+                }
+                merge_spans(spans)
+            }
+        }
+    }
+}
+
+/// Skips the `hw:` or `body:` scope a term starts with, unless nothing
+/// follows it, and tells which field it names.
+fn scope(chars: &mut Peekable<Chars>) -> Field {
+    for (prefix, field) in Field::PREFIXES {
+        let mut rest = chars.clone();
+        if prefix.chars().all(|p| rest.next() == Some(p))
+            && rest.peek().is_some_and(|&c| c != ' ' && c != '|')
+        {
+            *chars = rest;
+            return field;
+        }
+    }
+    Field::Any
+}
+
+/// Collects the literals any match of the expression contains. Runs of
+/// literals next to each other make one fragment. Alternations and classes,
+/// including the letters of a case-insensitive pattern, contribute none.
+fn required(hir: &Hir, fragments: &mut Vec<String>) {
+    match hir.kind() {
+        HirKind::Literal(literal) => fragments.extend(text(&literal.0)),
+        HirKind::Capture(capture) => required(&capture.sub, fragments),
+        HirKind::Repetition(repetition) if repetition.min > 0 => {
+            required(&repetition.sub, fragments)
+        }
+        HirKind::Concat(subs) => {
+            let mut run = vec![];
+            for sub in subs {
+                match sub.kind() {
+                    HirKind::Literal(literal) => run.extend_from_slice(&literal.0),
+                    _ => {
+                        fragments.extend(text(&std::mem::take(&mut run)));
+                        required(sub, fragments);
+                    }
+                }
+            }
+            fragments.extend(text(&run));
+        }
+        _ => {}
+    }
+}
+
+fn text(bytes: &[u8]) -> Option<String> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.is_empty() => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+impl Term {
+    fn new(text: &str, negated: bool) -> Term {
+        Term {
+            text: text.to_string(),
+            negated,
+            field: Field::Any,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Field, Query, Term};
+    use crate::normalize::Folding;
+
+    fn term(text: &str, negated: bool) -> Term {
+        Term::new(text, negated)
+    }
+
+    #[test]
+    fn parses_and_or_not_and_phrases() {
+        let query = Query::parse("bank  loan | \"river bank\" -money");
+        assert_eq!(
+            query.alternatives,
+            vec![
+                vec![term("bank", false), term("loan", false)],
+                vec![term("river bank", false), term("money", true)],
+            ]
+        );
+        assert_eq!(query.terms(), vec!["bank", "loan", "river bank"]);
+        assert!(query.matches("a loan from the bank"));
+        assert!(query.matches("on the river bank"));
+        assert!(!query.matches("river bank money"));
+        assert!(!query.matches("bank"));
+    }
+
+    #[test]
+    fn keeps_hyphens_tabs_and_unclosed_quotes() {
+        assert_eq!(
+            Query::parse("well-known - \t\t!").alternatives,
+            vec![vec![
+                term("well-known", false),
+                term("-", false),
+                term("\t\t!", false)
+            ]]
+        );
+        assert_eq!(Query::parse("\"a b").terms(), vec!["a b"]);
+        // nothing to look up
+        assert_eq!(
+            Query::parse("-a").alternatives,
+            vec![vec![term("-a", false)]]
+        );
+        assert_eq!(Query::parse("a | -b").alternatives.len(), 1);
+        assert_eq!(Query::parse("|").alternatives, vec![vec![term("|", false)]]);
+    }
+
+    #[test]
+    fn scopes_terms_to_a_field() {
+        let query = Query::parse("hw:cat -body:\"a dog\" body: hw:");
+        assert_eq!(
+            query.alternatives,
+            vec![vec![
+                Term {
+                    field: Field::Headword,
+                    ..term("cat", false)
+                },
+                Term {
+                    field: Field::Body,
+                    ..term("a dog", true)
+                },
+                term("body:", false),
+                term("hw:", false),
+            ]]
+        );
+        let query = Query::parse("hw:cat");
+        assert!(query.matches("cat\t猫"));
+        assert!(!query.matches("kitty\tcat"));
+        assert!(query.matches("cat"));
+        let query = Query::parse("body:cat");
+        assert!(query.matches("kitty\tcat"));
+        assert!(!query.matches("cat\t猫"));
+        assert!(!query.matches("cat"));
+        assert_eq!(query.spans("cat\tcat", Folding::None), [(4, 7)]);
+        let query = Query::parse("hw:cat cat");
+        assert_eq!(query.spans("cat\tcat", Folding::None), [(0, 3), (4, 7)]);
+    }
+
+    #[test]
+    fn extracts_the_literals_of_a_regex() {
+        let fragments = |pattern| Query::regex(pattern).unwrap().fragments().to_vec();
+        assert_eq!(fragments("^un.*able\\t"), ["un", "able\t"]);
+        assert_eq!(fragments("colou?r"), ["colo", "r"]);
+        assert_eq!(fragments("(同型)+ [a-z]{2}"), ["同型", " "]);
+        assert!(fragments("cat|dog").is_empty());
+        assert!(fragments("(?i)cat").is_empty());
+        let query = Query::regex("^un.*able\\t").unwrap();
+        assert!(query.matches("unbreakable\tこわれない"));
+        assert!(!query.matches("a unbreakable\tこわれない"));
+        assert_eq!(query.spans("unable\tx", Folding::None), [(0, 7)]);
+        assert!(Query::regex("(").is_err());
+    }
+
+    #[test]
+    fn matches_wildcards_against_headwords() {
+        let query = Query::wildcard("c?t");
+        assert_eq!(query.head(), "c");
+        assert!(query.matches("cat\t猫"));
+        assert!(query.matches("c猫t\tx"));
+        assert!(!query.matches("cart\t荷車"));
+        assert!(!query.matches("act\tcat"));
+        let query = Query::wildcard("*ness");
+        assert_eq!(query.head(), "");
+        assert_eq!(query.fragments(), ["ness\t"]);
+        assert!(query.matches("kindness\t親切"));
+        assert!(!query.matches("nessie\tx"));
+        assert!(Query::wildcard("a.b").matches("a.b\t"));
+        assert!(!Query::wildcard("a.b").matches("acb\t"));
+        assert!(Query::is_wildcard("??ough"));
+        assert!(Query::is_wildcard("*ness*"));
+        assert!(Query::is_wildcard("isomorph*"));
+        assert!(!Query::is_wildcard("what? no"));
+        assert!(!Query::is_wildcard("*"));
+        assert!(!Query::is_wildcard(""));
+    }
+}
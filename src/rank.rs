@@ -0,0 +1,120 @@
+use crate::hit::SearchHit;
+
+/// How well a hit matches its query, the best first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Relevance {
+    /// The headword is the query.
+    Headword,
+    /// The headword starts with the query.
+    HeadwordPrefix,
+    /// The query is a whole word of the headword.
+    HeadwordWord,
+    /// The query is a whole word of the translation.
+    BodyWord,
+    /// The query is somewhere else.
+    Substring,
+}
+
+impl Relevance {
+    /// Judged from the match spans, so that it agrees with the folding the
+    /// hit was found with.
+    pub fn of(hit: &SearchHit) -> Relevance {
+        let headword = &hit.headword;
+        if hit.headword_spans.contains(&(0, headword.len())) {
+            Relevance::Headword
+        } else if hit.headword_spans.iter().any(|&(start, _)| start == 0) {
+            Relevance::HeadwordPrefix
+        } else if hit.headword_spans.iter().any(|&s| is_word(headword, s)) {
+            Relevance::HeadwordWord
+        } else if hit.body_spans.iter().any(|&s| is_word(&hit.body, s)) {
+            Relevance::BodyWord
+        } else {
+            Relevance::Substring
+        }
+    }
+}
+
+/// Whether the span is neither preceded nor followed by a letter or digit.
+fn is_word(text: &str, (start, end): (usize, usize)) -> bool {
+    let before = text[..start].chars().next_back();
+    let after = text[end..].chars().next();
+    !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+}
+
+/// Sorts hits by relevance, then shorter entries first. Hits that compare
+/// equal keep their order, that of the dictionaries and of the text.
+pub fn rank(hits: &mut [SearchHit]) {
+    hits.sort_by_cached_key(|hit| (Relevance::of(hit), hit.headword.len() + hit.body.len()));
+}
+
+/// Sorts hits by the length of their headword in characters, then by
+/// headword, as for wildcard patterns.
+pub fn by_length(hits: &mut [SearchHit]) {
+    hits.sort_by_cached_key(|hit| (hit.headword.chars().count(), hit.headword.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rank, Relevance};
+    use crate::hit::SearchHit;
+    use crate::normalize::Folding;
+    use crate::query::Query;
+
+    fn hit(line: &str, query: &str) -> SearchHit {
+        SearchHit::new("test", 0, line, &Query::literal(query), Folding::None)
+    }
+
+    #[test]
+    fn ranks_headwords_before_translations() {
+        let query = "bank";
+        let mut hits = vec![
+            hit("embankment\t土手 [どて]", query),
+            hit("river\tthe bank of a river 川岸", query),
+            hit("bank\t銀行 [ぎんこう],土手 [どて]", query),
+            hit("river bank\t川岸 [かわぎし]", query),
+            hit("bankrupt\t破産 [はさん]", query),
+            hit("bank\t銀行 [ぎんこう]", query),
+            hit("data\tdatabank", query),
+        ];
+        let relevance = hits.iter().map(Relevance::of).collect::<Vec<_>>();
+        assert_eq!(
+            relevance,
+            vec![
+                Relevance::Substring,
+                Relevance::BodyWord,
+                Relevance::Headword,
+                Relevance::HeadwordWord,
+                Relevance::HeadwordPrefix,
+                Relevance::Headword,
+                Relevance::Substring,
+            ]
+        );
+        rank(&mut hits);
+        let headwords = hits.iter().map(|h| h.headword.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            headwords,
+            vec![
+                "bank",
+                "bank",
+                "bankrupt",
+                "river bank",
+                "river",
+                "data",
+                "embankment"
+            ]
+        );
+        assert_eq!(hits[0].body, "銀行 [ぎんこう]");
+    }
+
+    #[test]
+    fn words_are_delimited_in_japanese_too() {
+        assert_eq!(
+            Relevance::of(&hit("x\t同型 [どうけい]", "同型")),
+            Relevance::BodyWord
+        );
+        assert_eq!(
+            Relevance::of(&hit("x\t同型の", "同型")),
+            Relevance::Substring
+        );
+    }
+}
@@ -1,58 +1,161 @@
+use memmap2::Mmap;
 use rayon::prelude::*;
 use std::fs::File;
-use std::io::prelude::*;
-use std::io::SeekFrom;
 
-pub fn ngram_search(keyword: &String, ngram: &str, index: &str) -> Vec<(u32, u32)> {
-    if keyword.is_empty() {
-        return vec![];
-    }
-    let mut search_block = [0u8; BLOCK_SIZE as usize];
-    for (i, &v) in keyword.as_bytes().iter().enumerate() {
-        if i > search_block.len() - 1 {
-            break;
+use crate::header::{self, Offsets, HEADER_LEN};
+use crate::normalize::{normalize, Folding};
+use crate::query::Query;
+
+/// A generated file mapped into memory. `data` skips the header.
+struct Mapped {
+    mmap: Mmap,
+    offsets: Offsets,
+}
+
+impl Mapped {
+    /// Maps the file, or logs why it cannot be, so that the searches find
+    /// nothing in a dictionary whose files are missing.
+    fn open(path: &str) -> Option<Mapped> {
+        match File::open(path).and_then(|file| unsafe { Mmap::map(&file) }) {
+            Ok(mmap) if mmap.len() >= HEADER_LEN as usize => {
+                let offsets = Offsets::of(&mmap);
+                Some(Mapped { mmap, offsets })
+            }
+            Ok(_) => {
+                warn!("{}: no header", path);
+                None
+            }
+            Err(e) => {
+                warn!("{}: {}", path, e);
+                None
+            }
         }
-        search_block[i] = v;
     }
-    let mut ngramf = File::open(ngram).unwrap();
-    let begin = limit_left(&mut ngramf, &search_block) / BLOCK_SIZE * 8;
-    let end = limit_right(&mut ngramf, &search_block) / BLOCK_SIZE * 8;
-
-    let mut indexf = File::open(index).unwrap();
-    let mut nums = vec![];
-    for p in (begin..end).step_by(8) {
-        let (mut offset, mut len) = ([0u8; 4], [0u8; 4]);
-        indexf.seek(SeekFrom::Start(p)).unwrap();
-        indexf.read_exact(&mut offset).unwrap();
-        indexf.seek(SeekFrom::Start(p + 4)).unwrap();
-        indexf.read_exact(&mut len).unwrap();
-        nums.push((to_u32(&offset), to_u32(&len)));
+
+    fn data(&self) -> &[u8] {
+        &self.mmap[HEADER_LEN as usize..]
+    }
+
+    /// The (line offset, line length) record at the given position.
+    fn record(&self, i: usize) -> (u64, u32) {
+        let size = self.offsets.record_size();
+        let b = &self.data()[i * size..(i + 1) * size];
+        let (offset, len) = b.split_at(size - 4);
+        let offset = match self.offsets {
+            Offsets::Narrow => u32::from_be_bytes(offset.try_into().unwrap()) as u64,
+            Offsets::Wide => u64::from_be_bytes(offset.try_into().unwrap()),
+        };
+        (offset, u32::from_be_bytes(len.try_into().unwrap()))
+    }
+
+    fn records(&self) -> usize {
+        self.data().len() / self.offsets.record_size()
+    }
+
+    fn line(&self, (offset, len): (u64, u32)) -> &[u8] {
+        &self.data()[offset as usize..offset as usize + len as usize]
+    }
+}
+
+pub fn ngram_search(keyword: &String, ngram: &str, index: &str) -> Vec<(u64, u32)> {
+    if keyword.is_empty() {
+        return vec![];
     }
+    let mut search_block = [0u8; BLOCK_SIZE];
+    let head = truncate(keyword, BLOCK_SIZE).as_bytes();
+    search_block[..head.len()].copy_from_slice(head);
+    let Some(ngram) = Mapped::open(ngram) else {
+        return vec![];
+    };
+    let begin = limit_left(ngram.data(), &search_block);
+    let end = limit_right(ngram.data(), &search_block);
+
+    let Some(index) = Mapped::open(index) else {
+        return vec![];
+    };
+    let mut nums = (begin..end)
+        .map(|i| index.record(i))
+        .collect::<Vec<(u64, u32)>>();
     nums.sort();
     nums.dedup();
-    if nums.len() > 9999 {
-        info!(
-            "because it found too many hits of {}, will truncate to 9999",
-            nums.len()
-        );
-        nums.truncate(9999);
-    }
     nums
 }
 
-fn to_u32(b: &[u8; 4]) -> u32 {
-    let mut n = 0u32;
-    n |= b[0] as u32;
-    n <<= 8;
-    n |= b[1] as u32;
-    n <<= 8;
-    n |= b[2] as u32;
-    n <<= 8;
-    n |= b[3] as u32;
-    n
+/// How many n-grams of the index start with the keyword, telling how rare
+/// it is without loading the records.
+pub fn ngram_count(keyword: &str, ngram: &str) -> usize {
+    let mut search_block = [0u8; BLOCK_SIZE];
+    let head = truncate(keyword, BLOCK_SIZE).as_bytes();
+    search_block[..head.len()].copy_from_slice(head);
+    let Some(ngram) = Mapped::open(ngram) else {
+        return 0;
+    };
+    limit_right(ngram.data(), &search_block) - limit_left(ngram.data(), &search_block)
 }
 
-pub fn load_then_filter(input: &str, nums: &Vec<(u32, u32)>, text_file: &str) -> Vec<String> {
+/// Every line of the dictionary, for a full scan.
+pub fn all_records(headword: &str) -> Vec<(u64, u32)> {
+    let Some(headword) = Mapped::open(headword) else {
+        return vec![];
+    };
+    let mut nums = (0..headword.records())
+        .map(|i| headword.record(i))
+        .collect::<Vec<(u64, u32)>>();
+    nums.sort();
+    nums
+}
+
+/// Searches an index built from normalized text, folding the keyword the same
+/// way the index was.
+pub fn normalized_search(keyword: &str, ngram: &str, index: &str) -> Vec<(u64, u32)> {
+    let Some(mapped) = Mapped::open(ngram) else {
+        return vec![];
+    };
+    let folding = header::folding(&mapped.mmap);
+    ngram_search(&normalize(keyword, folding), ngram, index)
+}
+
+/// Lists the lines whose headword, the text before the tab, starts with the
+/// keyword, in headword order.
+pub fn prefix_search(keyword: &str, headword: &str, text: &str) -> Vec<(u64, u32)> {
+    if keyword.is_empty() {
+        return vec![];
+    }
+    let (Some(headword), Some(text)) = (Mapped::open(headword), Mapped::open(text)) else {
+        return vec![];
+    };
+    let title = |i: usize| {
+        let line = text.line(headword.record(i));
+        match line.iter().position(|&b| b == b'\t') {
+            Some(tabi) => &line[..tabi],
+            None => line,
+        }
+    };
+    let records = headword.records();
+    let key = keyword.as_bytes();
+    let begin = bisect(records, |i| title(i) < key);
+    let end = bisect(records, |i| title(i) < key || title(i).starts_with(key));
+    (begin..end).map(|i| headword.record(i)).collect()
+}
+
+/// Returns the first of `len` positions for which the predicate no longer
+/// holds.
+fn bisect(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let (mut fr, mut to) = (0, len);
+    while fr < to {
+        let mid = fr + (to - fr) / 2;
+        if pred(mid) {
+            fr = mid + 1;
+        } else {
+            to = mid;
+        }
+    }
+    fr
+}
+
+/// The lines containing the input, in the order of `nums`.
+#[cfg(test)]
+pub fn load_then_filter(input: &str, nums: &Vec<(u64, u32)>, text_file: &str) -> Vec<String> {
     let lines = load(nums, text_file);
     lines
         .par_iter()
@@ -61,102 +164,117 @@ pub fn load_then_filter(input: &str, nums: &Vec<(u32, u32)>, text_file: &str) ->
         .collect()
 }
 
-fn load(nums: &Vec<(u32, u32)>, text_file: &str) -> Vec<String> {
+/// Loads the lines matching the query, comparing them normalized as the
+/// query is, along with their offsets.
+pub fn filter_query(
+    query: &Query,
+    nums: &Vec<(u64, u32)>,
+    text_file: &str,
+    folding: Folding,
+) -> Vec<(u64, String)> {
+    let query = query.normalized(folding);
+    filter_lines(nums, text_file, |l| query.matches(&normalize(l, folding)))
+}
+
+/// Loads the lines the predicate keeps, along with their offsets.
+pub fn filter_lines(
+    nums: &Vec<(u64, u32)>,
+    text_file: &str,
+    keep: impl Fn(&str) -> bool + Sync,
+) -> Vec<(u64, String)> {
+    nums.par_iter()
+        .map(|&(offset, _)| offset)
+        .zip(load(nums, text_file))
+        .filter(|(_, l)| keep(l))
+        .collect()
+}
+
+fn load(nums: &Vec<(u64, u32)>, text_file: &str) -> Vec<String> {
     debug!("{:?} given:{}", nums, nums.len());
-    if nums.len() == 0 {
+    if nums.is_empty() {
         return vec![];
     }
-    nums
-        .par_iter()
-        .map(|&(offset, len)| {
-            let mut txtf = File::open(text_file).unwrap();
-            txtf.seek(SeekFrom::Start(offset as u64)).unwrap();
-            let mut line = vec![0u8; len as usize];
-            txtf.read(&mut line[..]).unwrap();
-            String::from_utf8(line).unwrap()
-        })
+    let Some(text) = Mapped::open(text_file) else {
+        return vec![];
+    };
+    nums.par_iter()
+        .map(|&num| String::from_utf8(text.line(num).to_vec()).unwrap())
         .collect::<Vec<String>>()
 }
 
 /// 12 byte length segmentation
-const BLOCK_SIZE: u64 = 12;
-
-fn limit_right(index: &mut File, head: &[u8; BLOCK_SIZE as usize]) -> u64 {
-    let (mut word, mut next) = ([0u8; BLOCK_SIZE as usize], [0u8; BLOCK_SIZE as usize]);
-    let blocks = index.metadata().unwrap().len() / BLOCK_SIZE;
-    let (mut fr, mut to) = (0u64, blocks * BLOCK_SIZE);
-    let mut cursor = (blocks / 2 - 1) * BLOCK_SIZE;
-    loop {
-        index.seek(SeekFrom::Start(cursor)).unwrap();
-        index.read_exact(&mut word).unwrap();
-        index.seek(SeekFrom::Start(cursor + BLOCK_SIZE)).unwrap();
-        index.read(&mut next).unwrap();
-
-        for (i, &c) in head.iter().enumerate().rev() {
-            if c != 0 {
-                break;
-            }
-            (word[i], next[i]) = (0, 0);
-        }
+const BLOCK_SIZE: usize = 12;
 
-        debug!("{:?}", String::from_utf8(word.iter().map(|&v| v).collect()));
-        if word <= *head && *head < next {
-            return cursor + BLOCK_SIZE;
-        }
-        if *head < word {
-            to = cursor;
-            let update = (cursor - fr) / BLOCK_SIZE / 2 * BLOCK_SIZE;
-            if update == 0 {
-                return cursor;
-            }
-            cursor -= update;
-        } else if word <= *head {
-            fr = cursor;
-            let update = (to - cursor) / BLOCK_SIZE / 2 * BLOCK_SIZE;
-            if update == 0 {
-                return cursor;
-            }
-            cursor += update;
-        }
+/// The longest prefix of the keyword within `len` bytes that does not split a
+/// character.
+fn truncate(keyword: &str, len: usize) -> &str {
+    let mut len = len.min(keyword.len());
+    while !keyword.is_char_boundary(len) {
+        len -= 1;
     }
+    &keyword[..len]
 }
 
-fn limit_left(index: &mut File, head: &[u8; BLOCK_SIZE as usize]) -> u64 {
-    let (mut word, mut next) = ([0u8; BLOCK_SIZE as usize], [0u8; BLOCK_SIZE as usize]);
-    let blocks = index.metadata().unwrap().len() / BLOCK_SIZE;
-    let (mut fr, mut to) = (0u64, blocks * BLOCK_SIZE);
-    let mut cursor = (blocks / 2 - 1) * BLOCK_SIZE;
-    loop {
-        index.seek(SeekFrom::Start(cursor)).unwrap();
-        index.read_exact(&mut word).unwrap();
-        index.seek(SeekFrom::Start(cursor + BLOCK_SIZE)).unwrap();
-        index.read(&mut next).unwrap();
-
-        for (i, &c) in head.iter().enumerate().rev() {
-            if c != 0 {
-                break;
-            }
-            (word[i], next[i]) = (0, 0);
+/// Compares a block with the search head, ignoring the bytes past the end of
+/// the head so that a shorter keyword matches as a prefix.
+fn masked(ngram: &[u8], i: usize, head: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+    let mut word: [u8; BLOCK_SIZE] = ngram[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE]
+        .try_into()
+        .unwrap();
+    for (i, &c) in head.iter().enumerate().rev() {
+        if c != 0 {
+            break;
         }
+        word[i] = 0;
+    }
+    word
+}
 
-        debug!("{:?}", String::from_utf8(word.iter().map(|&v| v).collect()));
-        if word < *head && *head <= next {
-            return cursor + BLOCK_SIZE;
-        }
-        if *head <= word {
-            to = cursor;
-            let update = (cursor - fr) / BLOCK_SIZE / 2 * BLOCK_SIZE;
-            if update == 0 {
-                return cursor;
-            }
-            cursor -= update;
-        } else if word < *head {
-            fr = cursor;
-            let update = (to - cursor) / BLOCK_SIZE / 2 * BLOCK_SIZE;
-            if update == 0 {
-                return cursor;
-            }
-            cursor += update;
+/// Number of blocks which sort before or equal to the head.
+fn limit_right(ngram: &[u8], head: &[u8; BLOCK_SIZE]) -> usize {
+    bisect(ngram.len() / BLOCK_SIZE, |i| {
+        masked(ngram, i, head) <= *head
+    })
+}
+
+/// Number of blocks which sort before the head.
+fn limit_left(ngram: &[u8], head: &[u8; BLOCK_SIZE]) -> usize {
+    bisect(ngram.len() / BLOCK_SIZE, |i| masked(ngram, i, head) < *head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mapped;
+    use crate::header::{self, Fingerprint, Kind, Offsets};
+    use crate::indexing::write_record;
+    use crate::normalize::Folding;
+    use crate::scratch::Scratch;
+    use std::io::Write;
+
+    #[test]
+    fn reads_back_wide_records() {
+        let scratch = Scratch::new("wide");
+        let path = scratch.file("WIDE_INDEX");
+        let records = [(0, 7), (u32::MAX as u64 + 1, 12), (u64::MAX - 3, u32::MAX)];
+        let mut file = std::fs::File::create(&path).unwrap();
+        let h = header::encode(
+            Kind::Index,
+            Offsets::Wide,
+            Folding::None,
+            &Fingerprint::default(),
+        );
+        file.write_all(&h).unwrap();
+        for (offset, len) in records {
+            write_record(&mut file, Offsets::Wide, offset, len).unwrap();
         }
+        drop(file);
+
+        let mapped = Mapped::open(&path).unwrap();
+        assert_eq!(mapped.offsets, Offsets::Wide);
+        assert_eq!(mapped.records(), 3);
+        assert_eq!(
+            (0..3).map(|i| mapped.record(i)).collect::<Vec<_>>(),
+            records
+        );
     }
 }
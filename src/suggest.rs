@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::search;
+
+/// Length in characters of the n-grams a query is cut into.
+const GRAM: usize = 3;
+
+/// N-grams found in more lines than this say little about a headword and
+/// are skipped.
+const COMMON: usize = 20000;
+
+/// How many of the lines sharing the most n-grams are compared.
+const CANDIDATES: usize = 300;
+
+/// A headword close to a query which found nothing.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Suggestion {
+    /// Edit distance from the query, in characters.
+    pub distance: usize,
+    pub headword: String,
+}
+
+/// Headwords close to the query, the closest first, ignoring case. The
+/// lines sharing the most n-grams with the lowercased query are looked up in
+/// the index and their headwords compared with it by edit distance.
+pub fn suggest(query: &str, ngram: &str, index: &str, text: &str) -> Vec<Suggestion> {
+    let chars = query.to_lowercase().chars().collect::<Vec<char>>();
+    if chars.len() < GRAM {
+        return vec![];
+    }
+    let mut shared: HashMap<(u64, u32), usize> = HashMap::new();
+    let mut grams = chars
+        .windows(GRAM)
+        .map(|w| w.iter().collect::<String>())
+        .collect::<Vec<String>>();
+    grams.sort();
+    grams.dedup();
+    for gram in grams {
+        if search::ngram_count(&gram, ngram) > COMMON {
+            continue;
+        }
+        for num in search::ngram_search(&gram, ngram, index) {
+            *shared.entry(num).or_default() += 1;
+        }
+    }
+    let mut nums = shared.into_iter().collect::<Vec<((u64, u32), usize)>>();
+    nums.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let nums = nums
+        .into_iter()
+        .take(CANDIDATES)
+        .map(|(num, _)| num)
+        .collect::<Vec<(u64, u32)>>();
+
+    let max = (chars.len() / 3).max(1);
+    let mut found = search::filter_lines(&nums, text, |_| true)
+        .into_iter()
+        .filter_map(|(_, line)| {
+            let headword = line.split('\t').next().unwrap_or_default().to_string();
+            let distance = distance(&chars, &headword.to_lowercase());
+            (distance <= max).then_some(Suggestion { distance, headword })
+        })
+        .collect::<Vec<Suggestion>>();
+    found.sort();
+    found.dedup();
+    found
+}
+
+/// Levenshtein distance between the characters of a query and a text.
+fn distance(query: &[char], text: &str) -> usize {
+    let mut previous = (0..=query.len()).collect::<Vec<usize>>();
+    for (i, t) in text.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &q) in query.iter().enumerate() {
+            let cost = usize::from(q != t);
+            current.push(
+                (previous[j] + cost)
+                    .min(previous[j + 1] + 1)
+                    .min(current[j] + 1),
+            );
+        }
+        previous = current;
+    }
+    previous[query.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::distance;
+
+    #[test]
+    fn counts_edits_in_characters() {
+        let chars = |s: &str| s.chars().collect::<Vec<char>>();
+        assert_eq!(distance(&chars("isomorhpism"), "isomorphism"), 2);
+        assert_eq!(distance(&chars("kitten"), "sitting"), 3);
+        assert_eq!(distance(&chars("同形"), "同型"), 1);
+        assert_eq!(distance(&chars(""), "abc"), 3);
+        assert_eq!(distance(&chars("abc"), ""), 3);
+    }
+}